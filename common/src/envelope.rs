@@ -0,0 +1,296 @@
+//! Envelope format of encrypted nvs values:
+//!
+//! `WMENC1:<hex(nonce[12] || ciphertext || tag[16])>`
+//!
+//! Values are encrypted with ChaCha20-Poly1305, nvs key is used as associated data,
+//! so encrypted value cannot be moved under another key.
+//!
+//! Key material and nonces are passed in by `Nvs`, so format doesn't depend on target
+//! hardware and it's tested on host (see crate docs).
+
+use alloc::{string::String, vec::Vec};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use sha2::{Digest, Sha256};
+
+pub const ENVELOPE_PREFIX: &str = "WMENC1:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// Value doesn't start with `ENVELOPE_PREFIX` or isn't valid hex
+    Malformed,
+
+    /// Wrong key or value was modified
+    Authentication,
+
+    /// Value is encrypted, but encryption isn't enabled
+    MissingKey,
+
+    /// Value is stored in plaintext, but plaintext values aren't accepted anymore
+    Plaintext,
+}
+
+/// String value read from nvs
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decoded {
+    /// Decrypted value (or plaintext value when encryption isn't enabled)
+    Value(String),
+
+    /// Plaintext value found while encryption is enabled, it should be encrypted again
+    Unencrypted(String),
+}
+
+/// Derives 256-bit key from device id (eFuse MAC) and optional user secret.
+///
+/// With empty `secret` key depends only on device id, which can be read from the device
+/// (and is broadcasted over the air), so it's only obfuscation, not confidentiality.
+pub fn derive_key(device_id: &[u8], secret: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"esp-hal-wifimanager nvs key");
+    hasher.update((device_id.len() as u32).to_le_bytes());
+    hasher.update(device_id);
+    hasher.update(secret);
+    hasher.finalize().into()
+}
+
+/// Length of envelope of `plaintext_len` bytes long value
+pub const fn envelope_len(plaintext_len: usize) -> usize {
+    ENVELOPE_PREFIX.len() + (NONCE_LEN + plaintext_len + TAG_LEN) * 2
+}
+
+pub fn is_envelope(value: &str) -> bool {
+    value.starts_with(ENVELOPE_PREFIX)
+}
+
+pub fn seal(key: &[u8; 32], nonce: [u8; NONCE_LEN], nvs_key: &str, plaintext: &[u8]) -> String {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: nvs_key.as_bytes(),
+            },
+        )
+        .expect("ChaCha20Poly1305 encryption cannot fail for in-memory buffers");
+
+    let mut envelope =
+        String::with_capacity(ENVELOPE_PREFIX.len() + (NONCE_LEN + ciphertext.len()) * 2);
+    envelope.push_str(ENVELOPE_PREFIX);
+    push_hex(&mut envelope, &nonce);
+    push_hex(&mut envelope, &ciphertext);
+    envelope
+}
+
+pub fn open(key: &[u8; 32], nvs_key: &str, envelope: &str) -> Result<Vec<u8>, EnvelopeError> {
+    let hex = envelope
+        .strip_prefix(ENVELOPE_PREFIX)
+        .ok_or(EnvelopeError::Malformed)?;
+    let bytes = decode_hex(hex).ok_or(EnvelopeError::Malformed)?;
+    if bytes.len() < NONCE_LEN + TAG_LEN {
+        return Err(EnvelopeError::Malformed);
+    }
+
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: nvs_key.as_bytes(),
+            },
+        )
+        .map_err(|_| EnvelopeError::Authentication)
+}
+
+/// Decodes stored string value.
+///
+/// Plaintext values are accepted while encryption is disabled or (during migration)
+/// when `plaintext_allowed` is true; otherwise they are rejected, so encrypted value
+/// cannot be silently replaced by plaintext one.
+pub fn decode(
+    key: Option<&[u8; 32]>,
+    plaintext_allowed: bool,
+    nvs_key: &str,
+    value: String,
+) -> Result<Decoded, EnvelopeError> {
+    if !is_envelope(&value) {
+        return match key {
+            None => Ok(Decoded::Value(value)),
+            Some(_) if plaintext_allowed => Ok(Decoded::Unencrypted(value)),
+            Some(_) => Err(EnvelopeError::Plaintext),
+        };
+    }
+
+    let key = key.ok_or(EnvelopeError::MissingKey)?;
+    let plaintext = open(key, nvs_key, &value)?;
+    String::from_utf8(plaintext)
+        .map(Decoded::Value)
+        .map_err(|_| EnvelopeError::Malformed)
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    for b in bytes {
+        out.push(HEX[(b >> 4) as usize] as char);
+        out.push(HEX[(b & 0xf) as usize] as char);
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let hi = (pair[0] as char).to_digit(16)?;
+            let lo = (pair[1] as char).to_digit(16)?;
+            Some(((hi << 4) | lo) as u8)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    const NONCE: [u8; NONCE_LEN] = [7; NONCE_LEN];
+
+    fn key() -> [u8; 32] {
+        derive_key(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc], b"secret")
+    }
+
+    #[test]
+    fn round_trip() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"{\"ssid\":\"test\"}");
+        assert!(is_envelope(&envelope));
+        assert_eq!(
+            open(&key(), "WIFI_SETUP", &envelope).unwrap(),
+            b"{\"ssid\":\"test\"}"
+        );
+    }
+
+    #[test]
+    fn envelope_len_matches_sealed_value() {
+        for len in [0, 1, 100] {
+            let envelope = seal(&key(), NONCE, "WIFI_SETUP", &alloc::vec![b'a'; len]);
+            assert_eq!(envelope.len(), envelope_len(len));
+        }
+    }
+
+    #[test]
+    fn empty_value_round_trip() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"");
+        assert_eq!(open(&key(), "WIFI_SETUP", &envelope).unwrap(), b"");
+    }
+
+    #[test]
+    fn tampered_value_is_rejected() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"password");
+
+        // flip one bit of every byte of the envelope (nonce, ciphertext and tag)
+        for i in ENVELOPE_PREFIX.len()..envelope.len() {
+            let mut tampered = envelope.clone().into_bytes();
+            tampered[i] = if tampered[i] == b'0' { b'1' } else { b'0' };
+            let tampered = String::from_utf8(tampered).unwrap();
+
+            assert_eq!(
+                open(&key(), "WIFI_SETUP", &tampered),
+                Err(EnvelopeError::Authentication)
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_value_is_rejected() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"password");
+        assert_eq!(
+            open(&key(), "WIFI_SETUP", &envelope[..envelope.len() - 2]),
+            Err(EnvelopeError::Authentication)
+        );
+        assert_eq!(
+            open(&key(), "WIFI_SETUP", &envelope[..envelope.len() - 1]),
+            Err(EnvelopeError::Malformed)
+        );
+        assert_eq!(
+            open(&key(), "WIFI_SETUP", ENVELOPE_PREFIX),
+            Err(EnvelopeError::Malformed)
+        );
+        assert_eq!(
+            open(&key(), "WIFI_SETUP", "WMENC1:zz"),
+            Err(EnvelopeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"password");
+        let other_device = derive_key(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd], b"secret");
+        let other_secret = derive_key(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc], b"");
+
+        assert_eq!(
+            open(&other_device, "WIFI_SETUP", &envelope),
+            Err(EnvelopeError::Authentication)
+        );
+        assert_eq!(
+            open(&other_secret, "WIFI_SETUP", &envelope),
+            Err(EnvelopeError::Authentication)
+        );
+    }
+
+    #[test]
+    fn value_moved_to_other_nvs_key_is_rejected() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"password");
+        assert_eq!(
+            open(&key(), "WIFI_SETUP_BAD", &envelope),
+            Err(EnvelopeError::Authentication)
+        );
+    }
+
+    #[test]
+    fn decode_without_encryption() {
+        assert_eq!(
+            decode(None, false, "WIFI_SETUP", "plain".to_string()),
+            Ok(Decoded::Value("plain".to_string()))
+        );
+
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"password");
+        assert_eq!(
+            decode(None, true, "WIFI_SETUP", envelope),
+            Err(EnvelopeError::MissingKey)
+        );
+    }
+
+    #[test]
+    fn decode_migrates_plaintext() {
+        assert_eq!(
+            decode(Some(&key()), true, "WIFI_SETUP", "plain".to_string()),
+            Ok(Decoded::Unencrypted("plain".to_string()))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_plaintext_after_migration() {
+        assert_eq!(
+            decode(Some(&key()), false, "WIFI_SETUP", "plain".to_string()),
+            Err(EnvelopeError::Plaintext)
+        );
+    }
+
+    #[test]
+    fn decode_encrypted() {
+        let envelope = seal(&key(), NONCE, "WIFI_SETUP", b"password");
+        assert_eq!(
+            decode(Some(&key()), false, "WIFI_SETUP", envelope),
+            Ok(Decoded::Value("password".to_string()))
+        );
+    }
+}
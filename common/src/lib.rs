@@ -0,0 +1,14 @@
+//! Target independent parts of `esp-hal-wifimanager`.
+//!
+//! They don't depend on esp-hal, so tests can run on host:
+//! `cargo +stable test-host` (from repository root).
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(feature = "encryption")]
+pub mod envelope;
+
+mod reconnect;
+pub use reconnect::ReconnectPolicy;
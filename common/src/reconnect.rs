@@ -0,0 +1,136 @@
+/// Exponential backoff of reconnect attempts.
+///
+/// N-th delay is `initial_delay * multiplier^n` (capped at `max_delay`), randomized by `jitter`,
+/// so devices that lost the same ap don't reconnect in lockstep. Sequence starts again
+/// after successful connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay after first failure (in ms)
+    pub initial_delay: u64,
+    pub multiplier: f32,
+
+    /// Max delay before jitter is applied (in ms)
+    pub max_delay: u64,
+
+    /// Random part of delay as fraction of it (0.2 means ±20%)
+    pub jitter: f32,
+}
+
+impl ReconnectPolicy {
+    /// Same delay after every failure (without backoff and jitter)
+    pub const fn fixed(delay: u64) -> Self {
+        Self {
+            initial_delay: delay,
+            multiplier: 1.0,
+            max_delay: delay,
+            jitter: 0.0,
+        }
+    }
+
+    /// Delay (in ms) before reconnect attempt `attempt` (counted from 0).
+    /// `random` should be uniformly distributed over whole `u32` range.
+    pub fn delay(&self, attempt: u32, random: u32) -> u64 {
+        let max_delay = self.max_delay as f32;
+        let mut delay = (self.initial_delay as f32).min(max_delay);
+        for _ in 0..attempt {
+            let next = (delay * self.multiplier).min(max_delay);
+
+            // delay doesn't grow anymore (max delay reached or multiplier <= 1)
+            if next <= delay {
+                break;
+            }
+            delay = next;
+        }
+
+        // maps random to -1.0..=1.0
+        let spread = random as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        (delay * (1.0 + jitter * spread)).max(0.0) as u64
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: 1000,
+            multiplier: 2.0,
+            max_delay: 60000,
+            jitter: 0.2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_jitter() -> ReconnectPolicy {
+        ReconnectPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_grows() {
+        let policy = no_jitter();
+        assert_eq!(policy.delay(0, 0), 1000);
+        assert_eq!(policy.delay(1, 0), 2000);
+        assert_eq!(policy.delay(2, 0), 4000);
+        assert_eq!(policy.delay(5, 0), 32000);
+    }
+
+    #[test]
+    fn reconnect_delay_capped() {
+        let policy = no_jitter();
+        assert_eq!(policy.delay(6, 0), 60000);
+        assert_eq!(policy.delay(100, 0), 60000);
+
+        let policy = ReconnectPolicy {
+            initial_delay: 90000,
+            ..no_jitter()
+        };
+        assert_eq!(policy.delay(0, 0), 60000);
+    }
+
+    #[test]
+    fn reconnect_delay_jitter_bounds() {
+        let policy = ReconnectPolicy::default();
+        for random in [0, 1, u32::MAX / 4, u32::MAX / 2, u32::MAX - 1, u32::MAX] {
+            let delay = policy.delay(0, random);
+            assert!((799..=1200).contains(&delay), "{delay}");
+
+            let delay = policy.delay(100, random);
+            assert!((47999..=72000).contains(&delay), "{delay}");
+        }
+
+        assert!(policy.delay(0, 0) < policy.delay(0, u32::MAX));
+    }
+
+    #[test]
+    fn reconnect_delay_fixed() {
+        let policy = ReconnectPolicy::fixed(5000);
+        for attempt in [0, 1, 10, u32::MAX] {
+            assert_eq!(policy.delay(attempt, 0), 5000);
+            assert_eq!(policy.delay(attempt, u32::MAX), 5000);
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_attempt_overflow() {
+        assert_eq!(no_jitter().delay(u32::MAX, 0), 60000);
+
+        // delay that never grows doesn't iterate over all attempts
+        let policy = ReconnectPolicy {
+            multiplier: 1.0,
+            ..no_jitter()
+        };
+        assert_eq!(policy.delay(u32::MAX, 0), 1000);
+
+        let policy = ReconnectPolicy {
+            initial_delay: 0,
+            ..no_jitter()
+        };
+        assert_eq!(policy.delay(u32::MAX, 0), 0);
+    }
+}
@@ -54,7 +54,11 @@ async fn main(spawner: Spawner) {
     wm_settings.wifi_conn_timeout = 30000;
     wm_settings.esp_reset_timeout = Some(300000); // 5min
 
-    let wifi_res = esp_hal_wifimanager::init_wm(
+    if let Some(events) = esp_hal_wifimanager::subscribe() {
+        spawner.must_spawn(wm_events_task(events));
+    }
+
+    let wifi_res = esp_hal_wifimanager::init_wm::<serde_json::Value>(
         wm_settings,
         &spawner,
         Some(&nvs),
@@ -72,3 +76,11 @@ async fn main(spawner: Spawner) {
         Timer::after_millis(15000).await;
     }
 }
+
+#[embassy_executor::task]
+async fn wm_events_task(mut events: esp_hal_wifimanager::WmEventSubscriber) {
+    loop {
+        let event = events.next_message_pure().await;
+        log::info!("wifimanager event: {event:?}");
+    }
+}
@@ -1,9 +1,7 @@
-use alloc::rc::Rc;
 use embassy_net::{Runner, Stack};
 use embassy_time::Duration;
 use esp_radio::wifi::Interface;
 
-use crate::structs::WmInnerSignals;
 #[embassy_executor::task]
 pub async fn run_dhcp_server(ap_stack: Stack<'static>) {
     let mut leaser = esp_hal_dhcp_server::simple_leaser::SimpleDhcpLeaser {
@@ -32,10 +30,8 @@ pub async fn run_dhcp_server(ap_stack: Stack<'static>) {
     }
 }
 
+/// Ap stack is reused by next setup portals, so its runner is never stopped
 #[embassy_executor::task]
-pub async fn ap_task(
-    mut runner: Runner<'static, Interface<'static>>,
-    signals: Rc<WmInnerSignals>,
-) {
-    embassy_futures::select::select(runner.run(), signals.end_signalled()).await;
+pub async fn ap_task(mut runner: Runner<'static, Interface<'static>>) {
+    runner.run().await
 }
@@ -1,13 +1,13 @@
-use crate::structs::WmInnerSignals;
+use crate::{structs::WmInnerSignals, ConnectionStatus};
 use alloc::{rc::Rc, string::String, vec::Vec};
 use core::str::FromStr;
-use embassy_futures::select::Either::{First, Second};
+use embassy_futures::select::Either3::{First, Second, Third};
 use embassy_time::Timer;
 use esp_hal::peripherals::BT;
 use esp_radio::ble::controller::BleConnector;
 use rand_core::OsRng;
 use trouble_host::{
-    att::{AttClient, AttReq},
+    att::{AttClient, AttErrorCode, AttReq},
     prelude::*,
 };
 
@@ -26,10 +26,48 @@ struct WifiService {
 
     #[characteristic(uuid = "22e997b5-0ac5-475d-ab6c-9c9568b6620a", read)]
     wifi_scan_res: heapless::String<512>,
+
+    /// Json error of last rejected setup string (see `ValidationError::to_json`), empty if accepted
+    #[characteristic(uuid = "5f1e6a0c-3b8d-4c52-9a7e-2d4b61c8f093", read)]
+    setup_status: heapless::String<256>,
+
+    /// Json of `ConnectionStatus`, notified on every change
+    #[characteristic(uuid = "c3a1f27e-6d04-4b8e-8f15-7a92e0d4b5c6", read, notify)]
+    conn_status: heapless::String<64>,
+
+    /// Json array of `WmSettings::provisioning_fields`
+    #[characteristic(uuid = "9b6694cd-c690-4224-a4c1-04ee7236bc08", read)]
+    provisioning_fields: heapless::String<512>,
+
+    /// Write `WmSettings::factory_reset_token` to do factory reset
+    #[characteristic(uuid = "08943af1-e43e-45b8-93d9-c181c90edf08", write)]
+    factory_reset: heapless::String<64>,
 }
 
+/// Runs ble setup until portal ends, then releases `bt` (see `WmInnerSignals::bt_released`)
 #[embassy_executor::task]
-pub async fn bluetooth_task(bt: BT<'static>, name: String, signals: Rc<WmInnerSignals>) {
+pub async fn bluetooth_task(
+    mut bt: BT<'static>,
+    name: String,
+    factory_reset_token: Option<String>,
+    signals: Rc<WmInnerSignals>,
+) {
+    run_ble(
+        bt.reborrow(),
+        &name,
+        factory_reset_token.as_deref(),
+        &signals,
+    )
+    .await;
+    signals.bt_released.signal(bt);
+}
+
+async fn run_ble(
+    bt: BT<'_>,
+    name: &str,
+    factory_reset_token: Option<&str>,
+    signals: &Rc<WmInnerSignals>,
+) {
     let Ok(connector) = BleConnector::new(bt, esp_radio::ble::Config::default()) else {
         log::error!("Cannot init ble connector");
         return;
@@ -58,24 +96,37 @@ pub async fn bluetooth_task(bt: BT<'static>, name: String, signals: Rc<WmInnerSi
     } = stack.build();
 
     let Ok(server) = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: &name,
+        name,
         appearance: &appearance::power_device::GENERIC_POWER_DEVICE,
     })) else {
         log::error!("[ble] New Server failed!");
         return;
     };
 
-    _ = embassy_futures::select::select3(ble_task(runner), stop_ble_task(&signals), async {
+    let fields = serde_json::to_string(signals.provisioning_fields()).unwrap_or_default();
+    match heapless::String::from_str(&fields) {
+        Ok(fields) => _ = server.set(&server.wifi_service.provisioning_fields, &fields),
+        Err(_) => log::warn!("[ble] Provisioning fields json is too long for ble characteristic!"),
+    }
+
+    _ = server.set(
+        &server.wifi_service.conn_status,
+        &conn_status_json(signals.conn_status()),
+    );
+
+    _ = embassy_futures::select::select3(ble_task(runner), signals.end_signalled(), async {
         loop {
-            match advertise(&name, &mut peripheral, &server).await {
+            match advertise(name, &mut peripheral, &server).await {
                 Ok(conn) => {
-                    let a = gatt_events_task(&server, &conn, &signals);
-                    let b = custom_task(&server, &conn, &stack, &signals);
+                    let a = gatt_events_task(&server, &conn, signals, factory_reset_token);
+                    let b = custom_task(&server, &conn, &stack, signals);
+                    let c = conn_status_task(&server, &conn, signals);
 
-                    let res = embassy_futures::select::select(a, b).await;
+                    let res = embassy_futures::select::select3(a, b, c).await;
                     match res {
                         First(_) => {}
                         Second(_) => {}
+                        Third(_) => {}
                     }
                 }
                 Err(e) => {
@@ -99,6 +150,7 @@ async fn gatt_events_task<P: PacketPool>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
     signals: &Rc<WmInnerSignals>,
+    factory_reset_token: Option<&str>,
 ) -> Result<(), Error> {
     let mut acc: Vec<u8> = Vec::new();
     let mut long_write_buf: Vec<u8> = Vec::new();
@@ -108,6 +160,7 @@ async fn gatt_events_task<P: PacketPool>(
         match event {
             GattConnectionEvent::Disconnected { reason } => break reason,
             GattConnectionEvent::Gatt { event } => {
+                let mut rejected = false;
                 match &event {
                     GattEvent::Read(e) => {
                         if e.handle() == server.wifi_service.wifi_scan_res.handle {
@@ -136,8 +189,16 @@ async fn gatt_events_task<P: PacketPool>(
                             acc.extend_from_slice(e.data());
                             if acc.last() == Some(&b'\0') {
                                 acc.pop();
-                                signals.wifi_conn_info_sig.signal(acc.clone());
-                                acc.clear();
+                                rejected = !submit_setup_string(&mut acc, server, signals);
+                            }
+                        } else if e.handle() == server.wifi_service.factory_reset.handle {
+                            let token = core::str::from_utf8(e.data()).unwrap_or_default();
+                            if factory_reset_token.is_some_and(|t| {
+                                crate::utils::token_matches(token.trim_end_matches('\0'), t)
+                            }) {
+                                signals.factory_reset_sig.signal(());
+                            } else {
+                                log::warn!("[gatt] invalid factory reset token");
                             }
                         }
                     }
@@ -161,8 +222,7 @@ async fn gatt_events_task<P: PacketPool>(
                             long_write_buf.clear();
                             if acc.last() == Some(&b'\0') {
                                 acc.pop();
-                                signals.wifi_conn_info_sig.signal(acc.clone());
-                                acc.clear();
+                                rejected = !submit_setup_string(&mut acc, server, signals);
                             }
                         }
                         _ => {}
@@ -170,7 +230,13 @@ async fn gatt_events_task<P: PacketPool>(
                     _ => {}
                 };
 
-                match event.accept() {
+                let reply = if rejected {
+                    event.reject(AttErrorCode::VALUE_NOT_ALLOWED)
+                } else {
+                    event.accept()
+                };
+
+                match reply {
                     Ok(reply) => reply.send().await,
                     Err(e) => log::warn!("[gatt] error sending response: {e:?}"),
                 };
@@ -183,6 +249,31 @@ async fn gatt_events_task<P: PacketPool>(
     Ok(())
 }
 
+/// Sends accumulated setup string to main task, returns false if it's invalid
+fn submit_setup_string(acc: &mut Vec<u8>, server: &Server<'_>, signals: &WmInnerSignals) -> bool {
+    let setup_info_buf = core::mem::take(acc);
+    let res = signals.validate_setup(&setup_info_buf);
+    let status = match res {
+        Ok(_) => String::new(),
+        Err(ref e) => e.to_json(),
+    };
+    _ = server.set(
+        &server.wifi_service.setup_status,
+        &heapless::String::from_str(&status).unwrap_or_default(),
+    );
+
+    match res {
+        Ok(setup_info) => {
+            signals.wifi_conn_info_sig.signal(setup_info);
+            true
+        }
+        Err(e) => {
+            log::warn!("[gatt] invalid setup string: {e}");
+            false
+        }
+    }
+}
+
 async fn advertise<'values, 'server, C: Controller>(
     name: &'values str,
     peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
@@ -231,12 +322,26 @@ async fn custom_task<C: Controller, P: PacketPool>(
     }
 }
 
-async fn stop_ble_task(signals: &Rc<WmInnerSignals>) {
+/// Notifies connected client about connection status changes
+async fn conn_status_task<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    signals: &Rc<WmInnerSignals>,
+) {
+    let Some(mut subscriber) = signals.conn_status_subscriber() else {
+        log::warn!("[ble] no free connection status subscriber");
+        return core::future::pending().await;
+    };
+
     loop {
-        let wifi_connected = signals.wifi_conn_res_sig.wait().await;
-        if wifi_connected {
-            log::debug!("Stopping ble task!");
-            return;
+        let status = conn_status_json(subscriber.next_message_pure().await);
+        if let Err(e) = server.wifi_service.conn_status.notify(conn, &status).await {
+            log::warn!("[gatt] error notifying connection status: {e:?}");
         }
     }
 }
+
+fn conn_status_json(status: ConnectionStatus) -> heapless::String<64> {
+    let json = serde_json::to_string(&status).unwrap_or_default();
+    heapless::String::from_str(&json).unwrap_or_default()
+}
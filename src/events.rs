@@ -0,0 +1,111 @@
+use crate::ConnFailReason;
+#[cfg(feature = "reachability")]
+use crate::Connectivity;
+use alloc::string::String;
+use core::net::Ipv4Addr;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    pubsub::{PubSubChannel, Subscriber},
+};
+
+/// Number of events kept for slow subscribers (oldest ones are dropped first)
+pub const WM_EVENTS_CAPACITY: usize = 8;
+
+/// Max number of event subscribers at the same time
+pub const WM_EVENTS_SUBSCRIBERS: usize = 6;
+
+pub type WmEventSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    WmEvent,
+    WM_EVENTS_CAPACITY,
+    WM_EVENTS_SUBSCRIBERS,
+    0,
+>;
+
+static WM_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    WmEvent,
+    WM_EVENTS_CAPACITY,
+    WM_EVENTS_SUBSCRIBERS,
+    0,
+> = PubSubChannel::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WmEvent {
+    /// Setup portal (ap and/or ble) started
+    PortalStarted,
+
+    /// Setup portal stopped (after successful connection or `WmSettings::portal_timeout`)
+    PortalStopped,
+
+    /// Setup string received from provisioning client
+    CredentialsReceived {
+        ssid: String,
+    },
+
+    Connecting {
+        ssid: String,
+    },
+    ConnectionFailed {
+        ssid: String,
+        reason: ConnFailReason,
+    },
+
+    /// Station connected to access point (`None` if value is unknown)
+    Connected {
+        bssid: Option<[u8; 6]>,
+        channel: Option<u8>,
+        rssi: Option<i8>,
+    },
+
+    GotIp {
+        ip: Ipv4Addr,
+    },
+
+    /// Station got different ip address (e.g. after reconnect)
+    IpChanged {
+        old: Ipv4Addr,
+        new: Ipv4Addr,
+    },
+
+    /// Station lost ip address (dhcp lease expired or link went down)
+    IpLost,
+    Disconnected {
+        reason: ConnFailReason,
+    },
+
+    /// Result of internet reachability check changed (see `WmSettings::reachability`)
+    #[cfg(feature = "reachability")]
+    ConnectivityChanged {
+        connectivity: Connectivity,
+    },
+
+    /// Radio stopped with `WmReturn::stop_radio`
+    RadioStopped,
+
+    /// Radio started again with `WmReturn::restart_radio`
+    RadioStarted,
+
+    /// Ota update progress (in bytes), published every few percent of written image
+    OtaProgress {
+        written: u32,
+        total: u32,
+    },
+
+    /// Wifimanager data removed from nvs by factory reset
+    FactoryReset,
+}
+
+/// Subscribes to wifimanager events, returns `None` if there are already
+/// `WM_EVENTS_SUBSCRIBERS` subscribers.
+///
+/// Can be called before `init_wm` to get events from setup portal.
+pub fn subscribe() -> Option<WmEventSubscriber> {
+    WM_EVENTS.subscriber().ok()
+}
+
+pub(crate) fn publish(event: WmEvent) {
+    log::debug!("[event] {event:?}");
+    WM_EVENTS.immediate_publisher().publish_immediate(event);
+}
@@ -0,0 +1,265 @@
+use crate::{
+    events::{self, WmEvent, WmEventSubscriber},
+    structs::{validate_data, DataValidator, WmReturn},
+    Nvs, Result, WmError, WmSettings,
+};
+use alloc::rc::Rc;
+use core::{
+    cell::{Cell, RefCell},
+    convert::Infallible,
+    pin::pin,
+};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::NoopRawMutex, Mutex as BlockingMutex},
+    signal::Signal,
+    watch::Watch,
+};
+use esp_hal::peripherals::WIFI;
+use serde::de::DeserializeOwned;
+
+/// Max number of tasks waiting on `WmHandle` at the same time
+pub const WM_HANDLE_WAITERS: usize = 4;
+
+/// State of wifimanager started with `init_wm_nonblocking`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WmState {
+    /// Connecting to saved networks
+    Starting,
+
+    /// Setup portal is running
+    Portal,
+
+    /// Connected to wifi, waiting for ip address
+    Connected,
+    GotIp([u8; 4]),
+
+    /// Station lost connection after `init_wm` returned (it's reconnecting)
+    Disconnected,
+
+    /// `init_wm` returned error (see `WmHandle::wait_return`)
+    Failed,
+}
+
+struct WmHandleInner {
+    state: Watch<NoopRawMutex, WmState, WM_HANDLE_WAITERS>,
+
+    /// Set when `init_wm` returned (result can be already taken)
+    finished: BlockingMutex<NoopRawMutex, Cell<bool>>,
+    result: BlockingMutex<NoopRawMutex, RefCell<Option<Result<WmReturn>>>>,
+}
+
+impl WmHandleInner {
+    fn state(&self) -> WmState {
+        self.state.try_get().unwrap_or(WmState::Starting)
+    }
+
+    fn set_state(&self, state: WmState) {
+        self.state.sender().send(state);
+    }
+
+    fn finished(&self) -> bool {
+        self.finished.lock(Cell::get)
+    }
+
+    /// Waits until state matches `f`, returns `None` if there are already
+    /// `WM_HANDLE_WAITERS` waiting tasks
+    async fn wait_state(&self, f: impl Fn(&WmState) -> bool) -> Option<WmState> {
+        let Some(mut receiver) = self.state.receiver() else {
+            log::error!("Too many tasks waiting on WmHandle (max {WM_HANDLE_WAITERS})");
+            return None;
+        };
+
+        Some(receiver.get_and(f).await)
+    }
+}
+
+/// Handle of wifimanager running in background (see `init_wm_nonblocking`).
+///
+/// State is updated from wifimanager events, also after `init_wm` returned.
+#[derive(Clone)]
+pub struct WmHandle {
+    inner: Rc<WmHandleInner>,
+}
+
+impl WmHandle {
+    pub fn state(&self) -> WmState {
+        self.inner.state()
+    }
+
+    /// Waits until wifi is connected, returns false if wifimanager failed
+    pub async fn wait_connected(&self) -> bool {
+        let state = self
+            .inner
+            .wait_state(|s| matches!(s, WmState::Connected | WmState::GotIp(_) | WmState::Failed))
+            .await;
+
+        matches!(state, Some(WmState::Connected | WmState::GotIp(_)))
+    }
+
+    /// Waits for station ip address, returns `None` if wifimanager failed
+    pub async fn wait_ip(&self) -> Option<[u8; 4]> {
+        let state = self
+            .inner
+            .wait_state(|s| matches!(s, WmState::GotIp(_) | WmState::Failed))
+            .await;
+
+        match state {
+            Some(WmState::GotIp(ip)) => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// Waits for `init_wm` to finish and takes its result (with data deserialized into `T`).
+    /// Result can be taken only once, next calls return `WmError::Other`.
+    pub async fn wait_return<T: DeserializeOwned>(&self) -> Result<WmReturn<T>> {
+        if !self.inner.finished() {
+            self.inner.wait_state(|_| self.inner.finished()).await;
+        }
+
+        match self.inner.result.lock(|r| r.borrow_mut().take()) {
+            Some(res) => res.map(WmReturn::with_data_type),
+            None => Err(WmError::Other),
+        }
+    }
+}
+
+struct InitArgs {
+    settings: WmSettings,
+    spawner: Spawner,
+    nvs: Option<Nvs>,
+    wifi: WIFI<'static>,
+    #[cfg(feature = "ble")]
+    bt: esp_hal::peripherals::BT<'static>,
+    ap_start_signal: Option<Rc<Signal<NoopRawMutex, ()>>>,
+    data_validator: DataValidator,
+}
+
+/// Same as `init_wm`, but it runs in background task and returns handle immediately,
+/// so application can work while wifimanager connects (or waits in setup portal).
+///
+/// Setup data is validated against `T`, use the same type in `WmHandle::wait_return`.
+pub fn init_wm_nonblocking<T: DeserializeOwned>(
+    settings: WmSettings,
+    spawner: &Spawner,
+    nvs: Option<&Nvs>,
+    wifi: WIFI<'static>,
+    #[cfg(feature = "ble")] bt: esp_hal::peripherals::BT<'static>,
+    ap_start_signal: Option<Rc<Signal<NoopRawMutex, ()>>>,
+) -> Result<WmHandle> {
+    let handle = WmHandle {
+        inner: Rc::new(WmHandleInner {
+            state: Watch::new(),
+            finished: BlockingMutex::new(Cell::new(false)),
+            result: BlockingMutex::new(RefCell::new(None)),
+        }),
+    };
+    handle.inner.set_state(WmState::Starting);
+
+    let args = InitArgs {
+        settings,
+        spawner: *spawner,
+        nvs: nvs.cloned(),
+        wifi,
+        #[cfg(feature = "ble")]
+        bt,
+        ap_start_signal,
+        data_validator: validate_data::<T>,
+    };
+
+    spawner.spawn(init_wm_task(args, handle.inner.clone())?);
+    Ok(handle)
+}
+
+#[embassy_executor::task]
+async fn init_wm_task(args: InitArgs, handle: Rc<WmHandleInner>) {
+    // subscribed before init, so no state change is missed
+    let events = events::subscribe();
+    if events.is_none() {
+        log::warn!("No free event subscriber, wifimanager state will be updated only at the end");
+    }
+
+    let init = crate::init_wm_inner(
+        args.settings,
+        &args.spawner,
+        args.nvs.as_ref(),
+        args.wifi,
+        #[cfg(feature = "ble")]
+        args.bt,
+        args.ap_start_signal,
+        args.data_validator,
+    );
+
+    let mut track_state = pin!(track_state(events, &handle));
+    let res = match select(init, track_state.as_mut()).await {
+        Either::First(res) => res,
+        Either::Second(never) => match never {},
+    };
+
+    let state = match res {
+        Ok(ref wm) => WmState::GotIp(wm.ip_address),
+        Err(ref e) => {
+            log::error!("Wifimanager failed: {e:?}");
+            WmState::Failed
+        }
+    };
+
+    // result is stored before state change, so `wait_return` sees it after wakeup
+    handle.result.lock(|r| *r.borrow_mut() = Some(res));
+    handle.finished.lock(|f| f.set(true));
+    handle.set_state(state);
+    if state == WmState::Failed {
+        return;
+    }
+
+    // state of running wifimanager is still tracked
+    match track_state.await {}
+}
+
+/// Updates handle state from wifimanager events (never returns)
+async fn track_state(events: Option<WmEventSubscriber>, handle: &WmHandleInner) -> Infallible {
+    let Some(mut events) = events else {
+        return core::future::pending().await;
+    };
+
+    let mut portal_running = false;
+    loop {
+        let state = match events.next_message_pure().await {
+            WmEvent::PortalStarted => {
+                portal_running = true;
+                WmState::Portal
+            }
+            WmEvent::PortalStopped => {
+                portal_running = false;
+                continue;
+            }
+            WmEvent::Connected { .. } | WmEvent::IpLost => WmState::Connected,
+            WmEvent::GotIp { ip } | WmEvent::IpChanged { new: ip, .. } => {
+                WmState::GotIp(ip.octets())
+            }
+
+            WmEvent::ConnectionFailed { .. } if handle.finished() => WmState::Disconnected,
+
+            // failed attempt (e.g. without dhcp lease) can follow connected event
+            WmEvent::ConnectionFailed { .. } | WmEvent::Disconnected { .. }
+                if !handle.finished() =>
+            {
+                if portal_running {
+                    WmState::Portal
+                } else {
+                    WmState::Starting
+                }
+            }
+            WmEvent::Disconnected { .. } | WmEvent::RadioStopped => WmState::Disconnected,
+            _ => continue,
+        };
+
+        // runtime setup portal doesn't change state of connected station
+        if state == WmState::Portal && handle.finished() {
+            continue;
+        }
+
+        handle.set_state(state);
+    }
+}
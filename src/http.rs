@@ -1,12 +1,19 @@
-use crate::structs::WmInnerSignals;
-use alloc::{format, rc::Rc, vec::Vec};
+use crate::{
+    structs::{ConnStatusSubscriber, WmInnerSignals},
+    ConnectionStatus, Result, WmSettings,
+};
+use alloc::{format, rc::Rc, string::String, vec::Vec};
 use embassy_executor::Spawner;
 use embassy_net::{tcp::TcpSocket, Stack};
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Timer};
 use embedded_io_async::Write;
 
 const WEB_TASK_POOL_SIZE: usize = 2;
-const HTTP_BUFFER_SIZE: usize = 2048;
+const HTTP_BUFFER_SIZE: usize = 4096;
+
+/// `WmEvent::OtaProgress` is published after every step (in percent) of written image
+#[cfg(feature = "ota")]
+const OTA_PROGRESS_STEP: usize = 5;
 
 struct HttpRequest<'a> {
     method: &'a str,
@@ -59,14 +66,59 @@ const UPDATE_PANEL_HTML: &str = include_minifier::include_minified!("src/update.
 #[cfg(not(feature = "ota"))]
 const UPDATE_PANEL_HTML: &str = "<html><body><p>OTA updates disabled</p></body></html>";
 
+fn get_header<'a>(request: &HttpRequest<'a>, name: &str) -> Option<&'a str> {
+    core::str::from_utf8(request.headers)
+        .ok()?
+        .split("\r\n")
+        .filter_map(|h| h.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// Checks if buffer contains headers and whole body of the request.
+/// Bodies that don't fit in `HTTP_BUFFER_SIZE` (ota) are read by their handlers.
+fn request_complete(buffer: &[u8]) -> bool {
+    let Some(req) = parse_http_request(buffer) else {
+        return false;
+    };
+
+    match (buffer.len() - req.body.len()).checked_add(content_length(&req)) {
+        Some(body_end) => body_end > HTTP_BUFFER_SIZE || buffer.len() >= body_end,
+        None => true,
+    }
+}
+
+fn content_length(request: &HttpRequest<'_>) -> usize {
+    get_header(request, "Content-Length")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
 async fn handle_request(
     request: HttpRequest<'_>,
     signals: &Rc<WmInnerSignals>,
-    wifi_panel_str: &'static str,
+    settings: &WmSettings,
 ) -> Vec<u8> {
+    // body that didn't fit in `HTTP_BUFFER_SIZE` was read only partially
+    if request.body.len() < content_length(&request) {
+        return create_http_response(
+            "413 Payload Too Large",
+            "text/plain",
+            "Request body too large",
+        );
+    }
+
     match (request.method, request.path) {
-        ("GET", "/") => create_http_response("200 OK", "text/html", wifi_panel_str),
+        ("GET", "/") => create_http_response("200 OK", "text/html", settings.wifi_panel),
         ("GET", "/update") => create_http_response("200 OK", "text/html", UPDATE_PANEL_HTML),
+        ("GET", "/fields") => {
+            let fields = serde_json::to_string(signals.provisioning_fields()).unwrap_or_default();
+            create_http_response("200 OK", "application/json", &fields)
+        }
+        ("GET", "/status") => {
+            let status = serde_json::to_string(&signals.conn_status()).unwrap_or_default();
+            create_http_response("200 OK", "application/json", &status)
+        }
         ("GET", "/list") => {
             let scan_res = signals.wifi_scan_res.try_lock();
             let resp = match scan_res {
@@ -76,20 +128,94 @@ async fn handle_request(
             create_http_response("200 OK", "text/plain", resp)
         }
         ("POST", "/setup") => {
-            let body_vec = request.body.to_vec();
-            signals.wifi_conn_info_sig.signal(body_vec);
-            create_http_response("200 OK", "text/plain", ".")
+            let setup_info = match signals.validate_setup(request.body) {
+                Ok(setup_info) => setup_info,
+                Err(e) => {
+                    log::warn!("Invalid setup data: {e}");
+                    return create_http_response(
+                        "400 Bad Request",
+                        "application/json",
+                        &e.to_json(),
+                    );
+                }
+            };
+
+            // subscribe before signalling, so result of this attempt isn't missed
+            let subscriber = signals.conn_status_subscriber();
+            signals.wifi_conn_info_sig.signal(setup_info);
+
+            let status = wait_for_conn_result(subscriber, signals, settings).await;
+            create_http_response(
+                "200 OK",
+                "application/json",
+                &setup_result_json(status, signals, settings),
+            )
+        }
+        ("POST", "/factory-reset") => {
+            let Some(ref token) = settings.factory_reset_token else {
+                return create_http_response("404 Not Found", "text/plain", "Not Found");
+            };
+
+            let authorized = get_header(&request, "Authorization")
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .is_some_and(|t| crate::utils::token_matches(t.trim(), token));
+            if !authorized {
+                return create_http_response("401 Unauthorized", "text/plain", "Unauthorized");
+            }
+
+            signals.factory_reset_sig.signal(());
+            create_http_response("200 OK", "text/plain", "Factory reset. Restarting...")
         }
         _ => create_http_response("404 Not Found", "text/plain", "Not Found"),
     }
 }
 
+/// Waits until connection attempt ends (connection and dhcp timeouts).
+/// Returns current status if it takes longer.
+async fn wait_for_conn_result(
+    subscriber: Option<ConnStatusSubscriber<'_>>,
+    signals: &WmInnerSignals,
+    settings: &WmSettings,
+) -> ConnectionStatus {
+    let Some(mut subscriber) = subscriber else {
+        return signals.conn_status();
+    };
+
+    let timeout = Duration::from_millis(settings.wifi_conn_timeout * 2 + 5000);
+    let res = with_timeout(timeout, async {
+        loop {
+            match subscriber.next_message_pure().await {
+                ConnectionStatus::Connecting | ConnectionStatus::Idle => {}
+                status => return status,
+            }
+        }
+    })
+    .await;
+
+    res.unwrap_or_else(|_| signals.conn_status())
+}
+
+/// Json of `ConnectionStatus` with station ip and hostname after successful connection
+fn setup_result_json(
+    status: ConnectionStatus,
+    signals: &WmInnerSignals,
+    settings: &WmSettings,
+) -> String {
+    let mut json = serde_json::to_value(status).unwrap_or_default();
+    if status == ConnectionStatus::Connected {
+        json["ip"] = serde_json::json!(signals.sta_ip());
+        json["hostname"] = serde_json::json!(settings.ssid);
+    }
+
+    json.to_string()
+}
+
 #[embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE)]
 async fn web_task(
     _id: usize,
     stack: Stack<'static>,
     signals: Rc<WmInnerSignals>,
-    wifi_panel_str: &'static str,
+    settings: WmSettings,
 ) {
     let fut = async {
         let mut rx_buffer = [0; 1024];
@@ -112,10 +238,7 @@ async fn web_task(
                     Ok(0) => break,
                     Ok(n) => {
                         total_read += n;
-                        if http_buffer[..total_read]
-                            .windows(4)
-                            .any(|w| w == b"\r\n\r\n")
-                        {
+                        if request_complete(&http_buffer[..total_read]) {
                             break;
                         }
                         if total_read >= HTTP_BUFFER_SIZE {
@@ -158,7 +281,7 @@ async fn web_task(
                         }
                     }
                 } else {
-                    let resp = handle_request(req, &signals, wifi_panel_str).await;
+                    let resp = handle_request(req, &signals, &settings).await;
                     let mut i = 0;
 
                     while i < resp.len() {
@@ -232,6 +355,7 @@ async fn handle_update_req(req: HttpRequest<'_>, socket: &mut TcpSocket<'_>) ->
     ota_buffer[..req.body.len()].copy_from_slice(req.body);
     let mut buffer_pos = req.body.len();
     let mut total = 0;
+    let mut published_percent = None;
 
     loop {
         match socket.read(&mut ota_buffer[buffer_pos..]).await {
@@ -265,6 +389,21 @@ async fn handle_update_req(req: HttpRequest<'_>, socket: &mut TcpSocket<'_>) ->
                     total += buffer_pos;
                     log::info!("read body: {} (total: {})", buffer_pos, total);
 
+                    // event is published every `OTA_PROGRESS_STEP` percent
+                    let percent = (total * 100)
+                        .checked_div(content_length)
+                        .unwrap_or(100)
+                        .min(100);
+                    if published_percent.is_none_or(|p| percent >= p + OTA_PROGRESS_STEP)
+                        || total >= content_length
+                    {
+                        published_percent = Some(percent);
+                        crate::events::publish(crate::WmEvent::OtaProgress {
+                            written: total as u32,
+                            total: content_length as u32,
+                        });
+                    }
+
                     let progress_msg = format!("PROGRESS:{},{}\n", total, content_length);
                     _ = socket.write_all(progress_msg.as_bytes()).await;
                     _ = socket.flush().await;
@@ -318,11 +457,14 @@ pub async fn run_http_server(
     spawner: &Spawner,
     ap_stack: Stack<'static>,
     signals: Rc<WmInnerSignals>,
-    wifi_panel_str: &'static str,
-) {
+    settings: WmSettings,
+) -> Result<()> {
     for id in 0..WEB_TASK_POOL_SIZE {
-        spawner.spawn(
-            web_task(id, ap_stack, signals.clone(), wifi_panel_str).expect("Web task failed"),
-        );
+        crate::utils::spawn_when_free(spawner, || {
+            web_task(id, ap_stack, signals.clone(), settings.clone())
+        })
+        .await?;
     }
+
+    Ok(())
 }
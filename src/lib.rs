@@ -8,25 +8,45 @@ compile_error!("enable at least one feature (\"ble\", \"ap\", \"env\")!");
 compile_error!("ESP32-S2 doesnt support BLE!");
 
 extern crate alloc;
+use alloc::collections::VecDeque;
 use alloc::rc::Rc;
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::net::Ipv4Addr;
 use core::ops::DerefMut;
 use core::str::FromStr;
 use embassy_executor::Spawner;
-use embassy_futures::select::Either;
-use embassy_net::{Config, DhcpConfig, Runner, StackResources};
+use embassy_futures::select::{Either, Either3};
+use embassy_net::{Config, ConfigV4, DhcpConfig, Runner, Stack, StackResources};
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use esp_hal::peripherals::WIFI;
 use esp_radio::wifi::{Interface, WifiController};
+use networks::SavedNetworks;
 use portable_atomic::{AtomicBool, Ordering};
-use structs::{AutoSetupSettings, Result, WmInnerSignals, WmReturn};
+use serde::de::DeserializeOwned;
+use structs::{
+    AutoSetupSettings, DataValidator, Result, WmCommand, WmCommands, WmInnerSignals, WmReturn,
+};
 
+pub use esp_radio::wifi::PowerSaveMode;
+pub use events::{
+    subscribe, WmEvent, WmEventSubscriber, WM_EVENTS_CAPACITY, WM_EVENTS_SUBSCRIBERS,
+};
+pub use handle::{init_wm_nonblocking, WmHandle, WmState, WM_HANDLE_WAITERS};
 pub use include_minifier::include_minified;
 pub use nvs::Nvs;
-pub use structs::{WmError, WmSettings};
+#[cfg(feature = "reachability")]
+pub use reachability::{Connectivity, ReachabilityCheck, ReachabilityProbe};
+pub use structs::{
+    ConnFailReason, ConnectionStatus, FactoryResetGpio, FieldKind, PortalFallback,
+    ProvisioningField, ReconnectPolicy, ResetPortalTrigger, WmError, WmPeripherals, WmSettings,
+};
 pub use utils::get_efuse_mac;
+pub use validation::ValidationError;
 
 #[cfg(feature = "ap")]
 mod http;
@@ -37,99 +57,269 @@ mod ap;
 #[cfg(feature = "ble")]
 mod bluetooth;
 
+#[cfg(feature = "encryption")]
+use esp_hal_wifimanager_common::envelope as crypto;
+
+#[cfg(feature = "reachability")]
+mod reachability;
+
+mod events;
+mod handle;
+mod networks;
 mod nvs;
 mod structs;
 mod utils;
+mod validation;
 
+/// Nvs key of saved networks list
 pub const WIFI_NVS_KEY: &str = "WIFI_SETUP";
+
+/// Nvs key where unreadable `WIFI_NVS_KEY` contents are moved to
+pub const WIFI_NVS_QUARANTINE_KEY: &str = "WIFI_SETUP_BAD";
+/// Nvs key of consecutive resets counter (see `WmSettings::reset_portal_trigger`)
+pub const RESET_COUNTER_NVS_KEY: &str = "WM_RESETS";
+/// Nvs key of marker set after first value is stored encrypted (see `Nvs::enable_encryption`).
+/// It isn't removed on factory reset.
+#[cfg(feature = "encryption")]
+pub const ENCRYPTION_MARKER_NVS_KEY: &str = "WM_ENCRYPTED";
+
+/// Nvs keys used by wifimanager (removed on factory reset)
+pub const WIFIMANAGER_NVS_KEYS: &[&str] =
+    &[WIFI_NVS_KEY, WIFI_NVS_QUARANTINE_KEY, RESET_COUNTER_NVS_KEY];
+
 static WIFI_CONTROLLER_ACTIVE: AtomicBool = AtomicBool::new(false);
 
+/// Station stack is created once and reused by next `init_wm` calls.
+///
+/// Esp-radio interfaces are just handles of wifi driver rx/tx queues (they don't own any
+/// controller state), so interface of first controller keeps working after controller is
+/// recreated and interfaces of next controllers are dropped.
+static STA_STACK: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Stack<'static>>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Sockets of station stack (reachability check adds dns and probe sockets)
+#[cfg(not(feature = "reachability"))]
+const STA_STACK_SOCKETS: usize = 3;
+#[cfg(feature = "reachability")]
+const STA_STACK_SOCKETS: usize = 5;
+
+/// Reset counter is checked only on first `init_wm` call after boot
+static RESET_COUNTER_CHECKED: AtomicBool = AtomicBool::new(false);
+
+/// Connects to saved network or starts setup portal and waits until it's done.
+///
+/// If `WmSettings::portal_timeout` is reached, portal is stopped and `WmError::WmTimeout`
+/// is returned. Radio is released then and `WIFI` (and `BT`) peripherals are returned with
+/// the error, so they can be passed to `init_wm` again later.
+///
+/// Setup data is validated against `T` and returned as `WmReturn::data`. Data is only
+/// deserialized, so `T` doesn't need to implement `Serialize`.
 #[allow(clippy::too_many_arguments)]
-pub async fn init_wm(
+pub async fn init_wm<T: DeserializeOwned>(
     settings: WmSettings,
     spawner: &Spawner,
     nvs: Option<&Nvs>,
     wifi: WIFI<'static>,
     #[cfg(feature = "ble")] bt: esp_hal::peripherals::BT<'static>,
     ap_start_signal: Option<Rc<Signal<NoopRawMutex, ()>>>,
+) -> Result<WmReturn<T>> {
+    init_wm_inner(
+        settings,
+        spawner,
+        nvs,
+        wifi,
+        #[cfg(feature = "ble")]
+        bt,
+        ap_start_signal,
+        structs::validate_data::<T>,
+    )
+    .await
+    .map(WmReturn::with_data_type)
+}
+
+/// `init_wm` with setup data validated by `data_validator` and returned as json
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn init_wm_inner(
+    settings: WmSettings,
+    spawner: &Spawner,
+    nvs: Option<&Nvs>,
+    wifi: WIFI<'static>,
+    #[cfg(feature = "ble")] mut bt: esp_hal::peripherals::BT<'static>,
+    ap_start_signal: Option<Rc<Signal<NoopRawMutex, ()>>>,
+    data_validator: DataValidator,
 ) -> Result<WmReturn> {
     let mut rng = esp_hal::rng::Rng::new();
-    let generated_ssid = settings.ssid.clone();
 
-    let (mut controller, interfaces) = esp_radio::wifi::new(wifi, Default::default())?;
+    // `wifi` is kept to recreate controller later (or to return it with `WmError::WmTimeout`),
+    // only one controller created from it is alive at a time
+    let (mut controller, interfaces) =
+        esp_radio::wifi::new(unsafe { wifi.clone_unchecked() }, Default::default())?;
     controller.set_power_saving(esp_radio::wifi::PowerSaveMode::None)?;
+    utils::track_connected_ap();
+
+    // station stack is created before setup, so dhcp can be checked during connection attempts
+    let sta_stack = match STA_STACK.lock(Cell::get) {
+        Some(sta_stack) => sta_stack,
+        None => {
+            let mut dhcp_config = DhcpConfig::default();
+            dhcp_config.hostname = heapless::String::from_str(&settings.ssid).ok();
+            let (sta_stack, runner) = embassy_net::new(
+                interfaces.station,
+                Config::dhcpv4(dhcp_config),
+                {
+                    static STATIC_CELL: static_cell::StaticCell<StackResources<STA_STACK_SOCKETS>> =
+                        static_cell::StaticCell::new();
+                    STATIC_CELL
+                        .uninit()
+                        .write(StackResources::<STA_STACK_SOCKETS>::new())
+                },
+                rng.random() as u64,
+            );
 
-    let wifi_setup = if let Some(nvs) = nvs {
-        match nvs.get::<String>(WIFI_NVS_KEY).await {
-            Ok(wifi_setup) => Some(serde_json::from_str::<AutoSetupSettings>(&wifi_setup)?),
-            Err(_) => None,
+            spawner.spawn(sta_task(runner)?);
+            STA_STACK.lock(|s| s.set(Some(sta_stack)));
+            sta_stack
         }
-    } else {
-        None
     };
 
-    let mut wifi_connected = false;
-    if let Some(ref wifi_setup) = wifi_setup {
-        log::debug!("Read wifi_setup from flash: {wifi_setup:?}");
-        controller.set_config(&wifi_setup.to_configuration()?)?;
+    // ap interface is kept for setup portal started after `init_wm` returned
+    #[cfg(feature = "ap")]
+    let mut ap_interface = Some(interfaces.access_point);
+
+    if let (Some(nvs), Some(gpio)) = (nvs, settings.factory_reset_gpio.as_ref()) {
+        if utils::factory_reset_gpio_held(gpio).await {
+            log::info!("Factory reset button held, removing saved networks...");
+            factory_reset(nvs, settings.factory_reset_keys).await?;
+        }
+    }
+
+    let force_portal = match (nvs, settings.reset_portal_trigger) {
+        (Some(nvs), Some(trigger)) if !RESET_COUNTER_CHECKED.swap(true, Ordering::AcqRel) => {
+            reset_portal_triggered(spawner, nvs, trigger).await?
+        }
+        _ => false,
+    };
+
+    let mut saved_networks = SavedNetworks::load(nvs).await;
+    log::debug!("Read saved networks from flash: {saved_networks:?}");
+
+    let mut wifi_setup = None;
+    if let Some(network) = saved_networks
+        .fast_connect_candidate()
+        .filter(|_| !force_portal)
+    {
+        log::info!("Trying to connect to cached ap of: {}", network.ssid);
+        controller.set_config(&network.to_attempt_configuration()?)?;
 
-        wifi_connected =
-            utils::try_to_wifi_connect(&mut controller, settings.wifi_conn_timeout).await;
+        if boot_connect(&mut controller, network, settings.fast_connect_timeout).await {
+            wifi_setup = Some(network.clone());
+        }
     }
 
-    let wifi_setup = if wifi_connected {
-        wifi_setup.expect("Internal error: wifi_setup should be Some when wifi_connected is true.")
+    let candidates = if wifi_setup.is_some() || force_portal || saved_networks.networks.is_empty() {
+        Vec::new()
     } else {
-        log::info!("Starting wifimanager with ssid: {generated_ssid}");
+        controller.set_config(&esp_radio::wifi::Config::Station(Default::default()))?;
+        match networks::scan(&mut controller, &saved_networks).await {
+            Ok(results) => saved_networks.visible(results.all()),
+            Err(e) => {
+                log::warn!("Boot scan failed, trying saved networks by priority: {e:?}");
+                saved_networks.networks.clone()
+            }
+        }
+    };
 
-        let wm_signals = Rc::new(WmInnerSignals::new());
-        if let Some(ap_start_signal) = ap_start_signal {
-            ap_start_signal.signal(());
+    for network in candidates {
+        log::info!("Trying to connect to saved network: {}", network.ssid);
+        controller.set_config(&network.to_attempt_configuration()?)?;
+
+        if boot_connect(&mut controller, &network, settings.wifi_conn_timeout).await {
+            wifi_setup = Some(network);
+            break;
         }
+    }
 
-        #[cfg(feature = "ap")]
-        let configuration = esp_radio::wifi::Config::AccessPointStation(
-            Default::default(),
-            esp_radio::wifi::ap::AccessPointConfig::default().with_ssid(generated_ssid.clone()),
-        );
+    if let (Some(nvs), Some(wifi_setup)) = (nvs, wifi_setup.as_ref()) {
+        if saved_networks.mark_success(&wifi_setup.ssid, utils::connected_ap()) {
+            saved_networks.save(nvs).await?;
+        }
+    }
 
-        #[cfg(not(feature = "ap"))]
-        let configuration = esp_radio::wifi::Config::Station(Default::default());
+    let wifi_setup = if let Some(wifi_setup) = wifi_setup {
+        wifi_setup
+    } else {
+        log::info!("Starting wifimanager with ssid: {}", settings.ssid);
 
-        controller.set_config(&configuration)?;
+        let wm_signals = Rc::new(WmInnerSignals::new(
+            data_validator,
+            settings.provisioning_fields,
+        ));
+        if let Some(ap_start_signal) = ap_start_signal {
+            ap_start_signal.signal(());
+        }
 
-        #[cfg(feature = "ap")]
-        utils::spawn_ap(
-            &mut rng,
+        let configuration = start_portal(
             spawner,
-            wm_signals.clone(),
-            settings.clone(),
-            interfaces.access_point,
+            &settings,
+            &mut controller,
+            &wm_signals,
+            Default::default(),
+            #[cfg(feature = "ap")]
+            &mut ap_interface,
+            #[cfg(feature = "ble")]
+            bt,
         )
         .await?;
 
         #[cfg(feature = "env")]
-        wm_signals
-            .wifi_conn_info_sig
-            .signal(env!("WM_CONN").as_bytes().to_vec());
+        match wm_signals.validate_setup(env!("WM_CONN").as_bytes()) {
+            Ok(setup_info) => wm_signals.wifi_conn_info_sig.signal(setup_info),
+            Err(e) => log::error!("Invalid WM_CONN setup string: {e}"),
+        }
 
-        #[cfg(feature = "ble")]
-        spawner.spawn(bluetooth::bluetooth_task(
-            bt,
-            generated_ssid,
-            wm_signals.clone(),
-        )?);
+        let mut worker_settings = settings.clone();
+        if force_portal {
+            worker_settings.saved_networks_retry_interval = None;
+        }
 
-        let wifi_setup = wifi_connection_worker(
-            settings.clone(),
-            wm_signals,
+        let res = wifi_connection_worker(
+            worker_settings,
+            wm_signals.clone(),
             nvs,
+            &mut saved_networks,
             &mut controller,
+            sta_stack,
             configuration,
+            None,
         )
-        .await?;
+        .await;
+
+        let wifi_setup = match res {
+            Ok(Some(wifi_setup)) => wifi_setup,
+            res => {
+                // radio is released, so init_wm can be started again
+                if let Err(e) = controller.stop_async().await {
+                    log::warn!("Failed to stop wifi controller: {e:?}");
+                }
+                drop(controller);
+
+                return Err(match res {
+                    Err(e) => e,
+                    _ => WmError::WmTimeout(WmPeripherals {
+                        wifi,
+                        #[cfg(feature = "ble")]
+                        bt: wm_signals.bt_released.wait().await,
+                    }),
+                });
+            }
+        };
+
+        // bt is kept for setup portal started after `init_wm` returned
+        #[cfg(feature = "ble")]
+        {
+            bt = wm_signals.bt_released.wait().await;
+        }
 
-        controller.set_config(&wifi_setup.to_configuration()?)?;
         if settings.esp_restart_after_connection {
             log::info!("Wifimanager reset after succesfull first connection...");
             Timer::after_millis(1000).await;
@@ -138,43 +328,62 @@ pub async fn init_wm(
 
         wifi_setup
     };
-    let wifi_configuration = wifi_setup.to_configuration()?;
-    let data = wifi_setup.data;
-
+    let mut wifi_configuration =
+        with_listen_interval(wifi_setup.to_configuration()?, settings.listen_interval);
     if let Err(e) = controller.disconnect_async().await {
         log::debug!(
             "Disconnect before connection task startup returned error (expected if not connected): {e:?}"
         );
     }
 
-    let mut dhcp_config = DhcpConfig::default();
-    dhcp_config.hostname = heapless::String::from_str(&settings.ssid).ok();
+    // station config (with listen interval) is set once after connection,
+    // network is already connected, so invalid listen interval doesn't fail init
+    if let Err(e) = controller.set_config(&wifi_configuration) {
+        log::error!(
+            "Cannot set station config with listen interval {:?}: {e:?}",
+            settings.listen_interval
+        );
 
-    let sta_config = Config::dhcpv4(dhcp_config);
-    let (sta_stack, runner) = embassy_net::new(
-        interfaces.station,
-        sta_config,
-        {
-            static STATIC_CELL: static_cell::StaticCell<StackResources<3>> =
-                static_cell::StaticCell::new();
-            STATIC_CELL.uninit().write(StackResources::<3>::new())
-        },
-        rng.random() as u64,
-    );
+        wifi_configuration = wifi_setup.to_configuration()?;
+        if let Err(e) = controller.set_config(&wifi_configuration) {
+            log::error!("Cannot set station config: {e:?}");
+        }
+    }
+
+    let ip_config = wifi_setup.to_ip_config(&settings.ssid);
+    sta_stack.set_config_v4(ip_config.clone());
+    let data = wifi_setup.data;
 
-    let stop_signal = Rc::new(Signal::new());
+    let commands = Rc::new(WmCommands::new());
     if WIFI_CONTROLLER_ACTIVE
         .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
         .is_err()
     {
         return Err(WmError::ControllerAlreadyActive);
     }
+
+    let factory_reset_keys = settings.factory_reset_keys;
+    let dhcp_timeout = settings.dhcp_timeout;
+    #[cfg(feature = "reachability")]
+    let reachability = settings.reachability;
+    let context = ConnectionContext {
+        wifi,
+        spawner: *spawner,
+        settings,
+        nvs: nvs.cloned(),
+        sta_stack,
+        data_validator,
+        #[cfg(feature = "ap")]
+        ap_interface,
+        #[cfg(feature = "ble")]
+        bt: Some(bt),
+    };
     let connection_token = connection(
-        settings.wifi_reconnect_time,
         controller,
         wifi_configuration,
-        stop_signal.clone(),
-        settings.wifi_conn_signal.clone(),
+        ip_config,
+        commands.clone(),
+        context,
     );
     match connection_token {
         Ok(token) => spawner.spawn(token),
@@ -183,98 +392,262 @@ pub async fn init_wm(
             return Err(WmError::TaskSpawnError);
         }
     }
-    spawner.spawn(sta_task(runner)?);
+
+    #[cfg(feature = "reachability")]
+    if let Some(check) = reachability {
+        spawner.spawn(reachability::reachability_task(
+            sta_stack,
+            check,
+            commands.clone(),
+        )?);
+    }
+
+    let ip_address = utils::wifi_wait_for_ip(&sta_stack).await;
+    events::publish(WmEvent::GotIp {
+        ip: ip_address.into(),
+    });
+
+    spawner.spawn(ip_monitor_task(
+        sta_stack,
+        ip_address.into(),
+        dhcp_timeout,
+        commands.clone(),
+    )?);
 
     Ok(WmReturn {
         sta_stack,
         data,
-        ip_address: utils::wifi_wait_for_ip(&sta_stack).await,
+        ip_address,
 
-        stop_signal,
+        commands,
+        nvs: nvs.cloned(),
+        factory_reset_keys,
     })
 }
 
+/// Increments consecutive resets counter, returns true if setup portal should be forced
+async fn reset_portal_triggered(
+    spawner: &Spawner,
+    nvs: &Nvs,
+    trigger: ResetPortalTrigger,
+) -> Result<bool> {
+    if trigger.resets <= 1 {
+        log::warn!(
+            "Reset portal trigger with {} resets forces setup portal on every boot",
+            trigger.resets
+        );
+        return Ok(true);
+    }
+
+    let stored = nvs.get::<u8>(RESET_COUNTER_NVS_KEY).await.ok();
+    let resets = stored.unwrap_or(0).saturating_add(1);
+
+    if resets >= trigger.resets {
+        log::info!("Reset {resets} times in a row, forcing setup portal");
+        if stored.is_some() {
+            _ = nvs.delete(RESET_COUNTER_NVS_KEY).await;
+        }
+        return Ok(true);
+    }
+
+    // nvs set replaces stored value, so counter is written once per boot
+    nvs.set(RESET_COUNTER_NVS_KEY, resets).await?;
+    spawner.spawn(reset_counter_task(nvs.clone(), trigger.window)?);
+
+    Ok(false)
+}
+
+/// Clears consecutive resets counter if esp runs longer than reset window
+#[embassy_executor::task]
+async fn reset_counter_task(nvs: Nvs, window: u64) {
+    Timer::after_millis(window).await;
+    if let Err(e) = nvs.delete(RESET_COUNTER_NVS_KEY).await {
+        log::warn!("Failed to clear reset counter: {e:?}");
+    }
+}
+
+/// Removes wifimanager data (and given user keys) from nvs
+pub async fn factory_reset(nvs: &Nvs, user_keys: &[&str]) -> Result<()> {
+    events::publish(WmEvent::FactoryReset);
+
+    for key in WIFIMANAGER_NVS_KEYS.iter().chain(user_keys.iter()) {
+        // key not found errors are expected here
+        if let Err(e) = nvs.delete(key).await {
+            log::debug!("Factory reset: cannot delete \"{key}\": {e:?}");
+        }
+    }
+
+    log::info!("Factory reset done!");
+    Ok(())
+}
+
+/// Runs setup until network is connected (it's returned then)
+/// or `WmSettings::portal_timeout` is reached (`None` is returned).
+///
+/// If `current_network` is set, its config is restored after failed connection attempts
+/// and station reconnects to it in the background (with `WmSettings::reconnect_policy` delays).
+/// If `CurrentNetwork::stop_on_reconnect` is set, `None` is returned once it's connected.
+#[allow(clippy::too_many_arguments)]
 async fn wifi_connection_worker(
     settings: WmSettings,
     wm_signals: Rc<WmInnerSignals>,
     nvs: Option<&Nvs>,
+    saved_networks: &mut SavedNetworks,
     controller: &mut WifiController<'static>,
-    mut configuration: esp_radio::wifi::Config,
-) -> Result<AutoSetupSettings> {
+    sta_stack: Stack<'static>,
+    configuration: esp_radio::wifi::Config,
+    current_network: Option<&CurrentNetwork<'_>>,
+) -> Result<Option<AutoSetupSettings>> {
     let start_time = Instant::now();
     let mut last_scan = Instant::MIN;
+    let mut last_saved_retry = Instant::now();
+    let mut visible_saved = Vec::new();
+    let mut backoff = utils::Backoff::new(settings.reconnect_policy);
+    let mut next_reconnect = Instant::now() + backoff.next_delay();
     loop {
-        if wm_signals.wifi_conn_info_sig.signaled() {
-            let setup_info_buf = wm_signals.wifi_conn_info_sig.wait().await;
-            let setup_info: AutoSetupSettings = serde_json::from_slice(&setup_info_buf)?;
-
-            log::debug!("trying to connect to: {setup_info:?}");
-            #[cfg(feature = "ap")]
-            {
-                let esp_radio::wifi::Config::AccessPointStation(ref mut station_conf, _) =
-                    configuration
-                else {
-                    return Err(WmError::Other);
-                };
-
-                *station_conf = setup_info.to_station()?;
-            }
-
-            #[cfg(not(feature = "ap"))]
-            {
-                let esp_radio::wifi::Config::Station(ref mut station_conf) = configuration else {
-                    return Err(WmError::Other);
-                };
-
-                *station_conf = setup_info.to_station()?;
+        if wm_signals.factory_reset_sig.signaled() {
+            wm_signals.factory_reset_sig.reset();
+            if let Some(nvs) = nvs {
+                factory_reset(nvs, settings.factory_reset_keys).await?;
             }
 
-            controller.set_config(&configuration)?;
-
-            let wifi_connected =
-                utils::try_to_wifi_connect(controller, settings.wifi_conn_timeout).await;
-
-            wm_signals.wifi_conn_res_sig.signal(wifi_connected);
+            log::info!("Wifimanager reset after factory reset...");
+            Timer::after_millis(1000).await;
+            esp_hal::system::software_reset();
+        }
 
-            if wifi_connected {
-                if let Some(nvs) = nvs {
-                    let Ok(setup_info_str) = core::str::from_utf8(&setup_info_buf) else {
-                        log::info!(
-                            "Wifimanager esp reset. Setup info string contains illegal characters."
-                        );
-                        Timer::after_millis(1000).await;
-                        esp_hal::system::software_reset();
-                    };
+        if wm_signals.wifi_conn_info_sig.signaled() {
+            let mut setup_info = wm_signals.wifi_conn_info_sig.wait().await;
+            events::publish(WmEvent::CredentialsReceived {
+                ssid: setup_info.ssid.clone(),
+            });
+            setup_info.pinned = setup_info.bssid.is_some() || setup_info.channel.is_some();
+            log::debug!("trying to connect to: {setup_info:?}");
+            wm_signals.set_conn_status(ConnectionStatus::Connecting);
 
-                    _ = nvs.delete(WIFI_NVS_KEY).await;
-                    nvs.set(WIFI_NVS_KEY, setup_info_str).await?;
+            match setup_connect(
+                &settings,
+                controller,
+                &configuration,
+                sta_stack,
+                &setup_info,
+                current_network,
+            )
+            .await
+            {
+                Ok(()) => {
+                    wm_signals.set_sta_ip(sta_stack.config_v4().map(|c| c.address.address()));
+                    wm_signals.set_conn_status(ConnectionStatus::Connected);
+                    saved_networks.upsert(setup_info.clone());
+                    return finish_wifi_connection_worker(
+                        &wm_signals,
+                        nvs,
+                        saved_networks,
+                        setup_info,
+                    )
+                    .await
+                    .map(Some);
                 }
+                Err(reason) => {
+                    log::warn!("Connection to {} failed: {reason:?}", setup_info.ssid);
+                    wm_signals.set_conn_status(ConnectionStatus::Failed(reason));
 
-                #[cfg(feature = "ap")]
-                esp_hal_dhcp_server::dhcp_close();
-
-                Timer::after_millis(1000).await;
-                wm_signals.signal_end();
-                return Ok(setup_info);
+                    // failed attempt dropped connection to current network
+                    next_reconnect = Instant::now();
+                }
             }
         }
 
         if last_scan.elapsed().as_millis() >= settings.wifi_scan_interval {
-            let scan_res = controller.scan_async(&Default::default()).await;
+            let scan_res = networks::scan(controller, saved_networks).await;
             let mut wifis = wm_signals.wifi_scan_res.lock().await;
             wifis.clear();
-            if let Ok(aps) = scan_res {
-                for ap in aps {
+            if let Ok(results) = scan_res {
+                for ap in results.aps.iter() {
                     _ = core::fmt::write(
                         wifis.deref_mut(),
                         format_args!("{}: {}\n", ap.ssid.as_str(), ap.signal_strength),
                     );
                 }
+
+                visible_saved = saved_networks.visible(results.all());
             }
 
             last_scan = Instant::now();
         }
 
+        if let Some(retry_interval) = settings.saved_networks_retry_interval {
+            if !visible_saved.is_empty() && last_saved_retry.elapsed().as_millis() >= retry_interval
+            {
+                for network in core::mem::take(&mut visible_saved) {
+                    log::info!("Retrying saved network: {}", network.ssid);
+                    let retry = embassy_futures::select::select(
+                        setup_connect(
+                            &settings,
+                            controller,
+                            &configuration,
+                            sta_stack,
+                            &network,
+                            current_network,
+                        ),
+                        credentials_received(&wm_signals),
+                    )
+                    .await;
+
+                    match retry {
+                        Either::First(Ok(())) => {
+                            wm_signals
+                                .set_sta_ip(sta_stack.config_v4().map(|c| c.address.address()));
+                            wm_signals.set_conn_status(ConnectionStatus::Connected);
+                            return finish_wifi_connection_worker(
+                                &wm_signals,
+                                nvs,
+                                saved_networks,
+                                network,
+                            )
+                            .await
+                            .map(Some);
+                        }
+                        Either::First(Err(_)) => next_reconnect = Instant::now(),
+                        Either::Second(()) => {
+                            log::info!("New credentials received, saved networks retry cancelled");
+                            _ = controller.disconnect_async().await;
+                            break;
+                        }
+                    }
+                }
+
+                last_saved_retry = Instant::now();
+            }
+        }
+
+        let reconnect = current_network.filter(|current| {
+            current.reconnects()
+                && Instant::now() >= next_reconnect
+                && !controller.is_connected().unwrap_or(false)
+        });
+        if let Some(current) = reconnect {
+            if reconnect_current(&settings, controller, &configuration, current).await {
+                if current.stop_on_reconnect {
+                    log::info!("Reconnected to previous network! Stopping portal..");
+                    stop_portal(&wm_signals).await;
+                    return Ok(None);
+                }
+
+                backoff.reset();
+            }
+
+            next_reconnect = Instant::now() + backoff.next_delay();
+        }
+
+        if let Some(portal_timeout) = settings.portal_timeout {
+            if start_time.elapsed().as_millis() >= portal_timeout {
+                log::info!("Wifimanager portal timeout reached! Stopping portal..");
+                stop_portal(&wm_signals).await;
+                return Ok(None);
+            }
+        }
+
         if let Some(reset_timeout) = settings.esp_reset_timeout {
             if start_time.elapsed().as_millis() >= reset_timeout {
                 log::info!("Wifimanager esp reset timeout reached! Resetting..");
@@ -287,130 +660,813 @@ async fn wifi_connection_worker(
     }
 }
 
+/// Network station uses when runtime setup portal starts
+struct CurrentNetwork<'a> {
+    configuration: &'a esp_radio::wifi::Config,
+    ip_config: &'a ConfigV4,
+
+    /// Portal is stopped once station reconnects (it was started because station
+    /// cannot reconnect, see `WmSettings::portal_fallback`)
+    stop_on_reconnect: bool,
+
+    /// Set while station ip config of setup attempt is applied
+    ip_config_replaced: Cell<bool>,
+}
+
+impl CurrentNetwork<'_> {
+    /// There is no mixed ap + eap mode, so enterprise network is reconnected
+    /// in the background only if portal stops after that
+    fn reconnects(&self) -> bool {
+        self.stop_on_reconnect
+            || !matches!(self.configuration, esp_radio::wifi::Config::EapStation(_))
+    }
+
+    fn restore_ip_config(&self, sta_stack: Stack<'static>) {
+        if self.ip_config_replaced.replace(false) {
+            sta_stack.set_config_v4(self.ip_config.clone());
+        }
+    }
+}
+
+/// Connects to network station used before runtime setup portal started.
+///
+/// Wifimanager configuration already contains its station (it's restored after failed
+/// attempts), only enterprise network has to be set (and wifimanager configuration is
+/// restored if it fails to connect).
+async fn reconnect_current(
+    settings: &WmSettings,
+    controller: &mut WifiController<'static>,
+    configuration: &esp_radio::wifi::Config,
+    current: &CurrentNetwork<'_>,
+) -> bool {
+    let eap = matches!(
+        current.configuration,
+        esp_radio::wifi::Config::EapStation(_)
+    );
+    if eap {
+        if let Err(e) = controller.set_config(current.configuration) {
+            log::warn!("Cannot set station config: {e:?}");
+            return false;
+        }
+    }
+
+    log::info!("Reconnecting to previous network..");
+    match utils::try_to_wifi_connect(controller, settings.wifi_conn_timeout).await {
+        Ok(()) => {
+            events::publish(connected_event(controller));
+            true
+        }
+        Err(reason) => {
+            log::info!("Failed to reconnect to previous network: {reason:?}");
+            if eap {
+                if let Err(e) = controller.set_config(configuration) {
+                    log::error!("Cannot restore wifimanager config: {e:?}");
+                }
+            }
+
+            false
+        }
+    }
+}
+
+/// Waits for credentials sent from setup portal (they're left in signal for worker)
+async fn credentials_received(wm_signals: &WmInnerSignals) {
+    let setup_info = wm_signals.wifi_conn_info_sig.wait().await;
+    wm_signals.wifi_conn_info_sig.signal(setup_info);
+}
+
+/// Saves successfully connected network and stops setup tasks
+async fn finish_wifi_connection_worker(
+    wm_signals: &WmInnerSignals,
+    nvs: Option<&Nvs>,
+    saved_networks: &mut SavedNetworks,
+    wifi_setup: AutoSetupSettings,
+) -> Result<AutoSetupSettings> {
+    if saved_networks.mark_success(&wifi_setup.ssid, utils::connected_ap()) {
+        if let Some(nvs) = nvs {
+            saved_networks.save(nvs).await?;
+        }
+    }
+
+    stop_portal(wm_signals).await;
+    Ok(wifi_setup)
+}
+
+/// Sets wifimanager configuration (given station config with setup ap) and spawns
+/// setup portal tasks (ap, http and ble)
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(not(any(feature = "ap", feature = "ble")), allow(unused_variables))]
+async fn start_portal(
+    spawner: &Spawner,
+    settings: &WmSettings,
+    controller: &mut WifiController<'static>,
+    wm_signals: &Rc<WmInnerSignals>,
+    station: esp_radio::wifi::sta::StationConfig,
+    #[cfg(feature = "ap")] ap_interface: &mut Option<Interface<'static>>,
+    #[cfg(feature = "ble")] bt: esp_hal::peripherals::BT<'static>,
+) -> Result<esp_radio::wifi::Config> {
+    #[cfg(feature = "ap")]
+    let configuration = esp_radio::wifi::Config::AccessPointStation(
+        station,
+        esp_radio::wifi::ap::AccessPointConfig::default().with_ssid(settings.ssid.clone()),
+    );
+
+    #[cfg(not(feature = "ap"))]
+    let configuration = esp_radio::wifi::Config::Station(station);
+
+    controller.set_config(&configuration)?;
+
+    #[cfg(feature = "ap")]
+    utils::spawn_ap(
+        &mut esp_hal::rng::Rng::new(),
+        spawner,
+        wm_signals.clone(),
+        settings.clone(),
+        ap_interface,
+    )
+    .await?;
+
+    // bt is released by ble task of previous portal when it finishes, so its pool slot is free
+    #[cfg(feature = "ble")]
+    spawner.spawn(bluetooth::bluetooth_task(
+        bt,
+        settings.ssid.clone(),
+        settings.factory_reset_token.clone(),
+        wm_signals.clone(),
+    )?);
+
+    events::publish(WmEvent::PortalStarted);
+    Ok(configuration)
+}
+
+/// Runs setup portal started by `WmReturn::start_portal` until new network is connected,
+/// portal times out or `WmReturn::stop_portal` is called. Station is kept on (or reconnected
+/// to) current network in the background. If it was disconnected when portal started,
+/// portal stops once it's connected again.
+///
+/// Other commands received while portal is running are added to `pending_commands`.
+async fn runtime_portal(
+    context: &mut ConnectionContext,
+    controller: &mut WifiController<'static>,
+    configuration: &esp_radio::wifi::Config,
+    ip_config: &ConfigV4,
+    commands: &WmCommands,
+    pending_commands: &mut VecDeque<WmCommand>,
+) -> Result<PortalEnd> {
+    #[cfg(feature = "ble")]
+    let Some(bt) = context.bt.take() else {
+        log::error!("BT peripheral wasn't released by previous setup portal");
+        return Err(WmError::Other);
+    };
+
+    log::info!(
+        "Starting wifimanager portal with ssid: {}",
+        context.settings.ssid
+    );
+
+    // there is no mixed ap + eap mode, so enterprise station is paused until portal stops
+    let station = match configuration {
+        esp_radio::wifi::Config::Station(station) => station.clone(),
+        _ => Default::default(),
+    };
+
+    let wm_signals = Rc::new(WmInnerSignals::new(
+        context.data_validator,
+        context.settings.provisioning_fields,
+    ));
+    let portal_configuration = start_portal(
+        &context.spawner,
+        &context.settings,
+        controller,
+        &wm_signals,
+        station,
+        #[cfg(feature = "ap")]
+        &mut context.ap_interface,
+        #[cfg(feature = "ble")]
+        bt,
+    )
+    .await?;
+
+    // retrying saved networks would drop current connection
+    let mut worker_settings = context.settings.clone();
+    worker_settings.esp_reset_timeout = None;
+    let connected = controller.is_connected().unwrap_or(false);
+    if connected {
+        worker_settings.saved_networks_retry_interval = None;
+    }
+
+    let current_network = CurrentNetwork {
+        configuration,
+        ip_config,
+        stop_on_reconnect: !connected,
+        ip_config_replaced: Cell::new(false),
+    };
+
+    let mut saved_networks = SavedNetworks::load(context.nvs.as_ref()).await;
+    let worker = wifi_connection_worker(
+        worker_settings,
+        wm_signals.clone(),
+        context.nvs.as_ref(),
+        &mut saved_networks,
+        controller,
+        context.sta_stack,
+        portal_configuration,
+        Some(&current_network),
+    );
+
+    let stop_requested = async {
+        loop {
+            match commands.receive().await {
+                WmCommand::StopPortal => break,
+                WmCommand::StartPortal => log::info!("Setup portal is already running"),
+                command => {
+                    log::info!("{command:?} will be handled after setup portal stops");
+                    pending_commands.push_back(command);
+                }
+            }
+        }
+    };
+
+    let res = match embassy_futures::select::select(worker, stop_requested).await {
+        Either::First(Ok(Some(network))) => Ok(PortalEnd::Connected(network)),
+        Either::First(Ok(None)) => Ok(PortalEnd::TimedOut),
+        Either::First(Err(e)) => {
+            stop_portal(&wm_signals).await;
+            Err(e)
+        }
+        Either::Second(()) => {
+            log::info!("Stopping wifimanager portal..");
+            stop_portal(&wm_signals).await;
+            Ok(PortalEnd::Stopped)
+        }
+    };
+
+    // attempt could be cancelled before its ip config was reverted
+    if !matches!(res, Ok(PortalEnd::Connected(_))) {
+        current_network.restore_ip_config(context.sta_stack);
+    }
+
+    // ble task has to finish before next portal is started
+    #[cfg(feature = "ble")]
+    {
+        context.bt = Some(wm_signals.bt_released.wait().await);
+    }
+
+    res
+}
+
+/// How runtime setup portal ended. Station ip config of current network is restored
+/// if new network wasn't connected.
+enum PortalEnd {
+    /// New network was connected and saved
+    Connected(AutoSetupSettings),
+
+    /// Portal timed out (or station reconnected to current network)
+    TimedOut,
+
+    /// Portal was stopped with `WmReturn::stop_portal`
+    Stopped,
+}
+
+/// Stops setup portal tasks (dhcp server, http server and ble)
+async fn stop_portal(wm_signals: &WmInnerSignals) {
+    #[cfg(feature = "ap")]
+    esp_hal_dhcp_server::dhcp_close();
+
+    Timer::after_millis(1000).await;
+    wm_signals.signal_end();
+    events::publish(WmEvent::PortalStopped);
+}
+
+/// Connects to network during setup and waits for ip address.
+/// Wifimanager configuration (and ip config of `current_network`) is restored if connection fails.
+async fn setup_connect(
+    settings: &WmSettings,
+    controller: &mut WifiController<'static>,
+    configuration: &esp_radio::wifi::Config,
+    sta_stack: Stack<'static>,
+    network: &AutoSetupSettings,
+    current_network: Option<&CurrentNetwork<'_>>,
+) -> core::result::Result<(), ConnFailReason> {
+    if let Err(e) = set_setup_station(controller, configuration, network) {
+        log::warn!("Cannot set station config: {e:?}");
+        return Err(ConnFailReason::Other);
+    }
+
+    events::publish(WmEvent::Connecting {
+        ssid: network.ssid.clone(),
+    });
+
+    let mut res = utils::try_to_wifi_connect(controller, settings.wifi_conn_timeout).await;
+    if res.is_ok() {
+        events::publish(connected_event(controller));
+        if let Some(current) = current_network {
+            current.ip_config_replaced.set(true);
+        }
+        sta_stack.set_config_v4(network.to_ip_config(&settings.ssid));
+
+        let ip_timeout = Duration::from_millis(settings.wifi_conn_timeout);
+        match with_timeout(ip_timeout, sta_stack.wait_config_up()).await {
+            Ok(_) => {
+                if let Some(config) = sta_stack.config_v4() {
+                    events::publish(WmEvent::GotIp {
+                        ip: config.address.address(),
+                    });
+                }
+            }
+            Err(_) => {
+                log::warn!("Didn't get ip address from {}", network.ssid);
+                _ = controller.disconnect_async().await;
+                res = Err(ConnFailReason::DhcpFailed);
+            }
+        }
+    }
+
+    if let Err(reason) = res {
+        events::publish(WmEvent::ConnectionFailed {
+            ssid: network.ssid.clone(),
+            reason,
+        });
+
+        if let Err(e) = controller.set_config(configuration) {
+            log::error!("Cannot restore wifimanager config: {e:?}");
+        }
+        if let Some(current) = current_network {
+            current.restore_ip_config(sta_stack);
+        }
+    }
+
+    res
+}
+
+/// Connects to saved network during boot (config has to be already set)
+async fn boot_connect(
+    controller: &mut WifiController<'static>,
+    network: &AutoSetupSettings,
+    timeout: u64,
+) -> bool {
+    events::publish(WmEvent::Connecting {
+        ssid: network.ssid.clone(),
+    });
+
+    match utils::try_to_wifi_connect(controller, timeout).await {
+        Ok(()) => {
+            events::publish(connected_event(controller));
+            true
+        }
+        Err(reason) => {
+            events::publish(WmEvent::ConnectionFailed {
+                ssid: network.ssid.clone(),
+                reason,
+            });
+            false
+        }
+    }
+}
+
+/// Connected event with info of access point station is connected to
+fn connected_event(controller: &WifiController<'static>) -> WmEvent {
+    let ap = utils::connected_ap();
+    WmEvent::Connected {
+        bssid: ap.map(|(bssid, _)| bssid),
+        channel: ap.map(|(_, channel)| channel),
+        rssi: controller.rssi().ok().map(|rssi| rssi as i8),
+    }
+}
+
+/// Sets controller config for connection attempt during setup (wifimanager configuration
+/// with station replaced by `network`).
+///
+/// There is no mixed ap + eap mode, so for enterprise networks ap is stopped
+/// until wifimanager configuration is set again.
+fn set_setup_station(
+    controller: &mut WifiController<'static>,
+    configuration: &esp_radio::wifi::Config,
+    network: &AutoSetupSettings,
+) -> Result<()> {
+    if network.eap.is_some() {
+        controller.set_config(&network.to_attempt_configuration()?)?;
+        return Ok(());
+    }
+
+    let mut configuration = configuration.clone();
+    set_station_config(&mut configuration, network.to_attempt_station()?)?;
+    controller.set_config(&configuration)?;
+    Ok(())
+}
+
+/// Replaces station part of the wifimanager configuration
+fn set_station_config(
+    configuration: &mut esp_radio::wifi::Config,
+    station: esp_radio::wifi::sta::StationConfig,
+) -> Result<()> {
+    #[cfg(feature = "ap")]
+    let esp_radio::wifi::Config::AccessPointStation(ref mut station_conf, _) = configuration
+    else {
+        return Err(WmError::Other);
+    };
+
+    #[cfg(not(feature = "ap"))]
+    let esp_radio::wifi::Config::Station(ref mut station_conf) = configuration
+    else {
+        return Err(WmError::Other);
+    };
+
+    *station_conf = station;
+    Ok(())
+}
+
+/// State needed by connection task to start setup portal again
+struct ConnectionContext {
+    /// Wifi peripheral controllers are created from (see `new_controller`)
+    wifi: WIFI<'static>,
+    spawner: Spawner,
+    settings: WmSettings,
+    nvs: Option<Nvs>,
+    sta_stack: Stack<'static>,
+    data_validator: DataValidator,
+
+    #[cfg(feature = "ap")]
+    ap_interface: Option<Interface<'static>>,
+
+    /// Taken by runtime setup portal and given back after its ble task finishes
+    #[cfg(feature = "ble")]
+    bt: Option<esp_hal::peripherals::BT<'static>>,
+}
+
 #[embassy_executor::task]
 async fn connection(
-    wifi_reconnect_time: u64,
-    controller: WifiController<'static>,
-    configuration: esp_radio::wifi::Config,
-    stop_signal: Rc<Signal<CriticalSectionRawMutex, bool>>,
-    wifi_conn_signal: Option<Rc<Signal<CriticalSectionRawMutex, bool>>>,
+    mut controller: WifiController<'static>,
+    mut configuration: esp_radio::wifi::Config,
+    mut ip_config: ConfigV4,
+    commands: Rc<WmCommands>,
+    mut context: ConnectionContext,
 ) {
+    let mut backoff = utils::Backoff::new(context.settings.reconnect_policy);
+    let mut power_save = context.settings.power_save;
+
+    // setup runs without power save, configured mode is applied once station is handed over
+    set_power_save(&mut controller, power_save);
+
     let mut controller_slot = Some(controller);
-    let mut stopped = false;
+    let mut pending_commands = VecDeque::new();
+    let mut disconnection = Disconnection::default();
 
     loop {
-        if stopped {
-            loop {
-                if !stop_signal.wait().await {
-                    break;
-                }
+        let Some(controller) = controller_slot.as_mut() else {
+            let command = match pending_commands.pop_front() {
+                Some(command) => command,
+                None => commands.receive().await,
+            };
+            if let WmCommand::SetPowerSave(mode) = command {
+                // applied when radio is restarted
+                power_save = mode;
+                continue;
             }
 
-            if WIFI_CONTROLLER_ACTIVE
-                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-                .is_err()
-            {
-                log::info!("Skipped wifi restart because another controller owner is active.");
-                Timer::after(Duration::from_millis(wifi_reconnect_time)).await;
+            if !matches!(command, WmCommand::RestartRadio | WmCommand::StartPortal) {
                 continue;
             }
 
-            // just steal wifi, fuck it lmao
-            match esp_radio::wifi::new(
-                unsafe { esp_hal::peripherals::WIFI::steal() },
-                Default::default(),
-            ) {
-                Ok((mut new_controller, _)) => {
-                    if let Err(e) =
-                        new_controller.set_power_saving(esp_radio::wifi::PowerSaveMode::None)
-                    {
-                        log::info!(
-                            "Failed to disable power save while restarting wifi controller: {e:?}"
-                        );
-                        WIFI_CONTROLLER_ACTIVE.store(false, Ordering::Release);
-                        Timer::after(Duration::from_millis(wifi_reconnect_time)).await;
-                        continue;
-                    }
-
-                    if let Err(e) = new_controller.set_config(&configuration) {
-                        log::info!("Failed to set config while restarting wifi controller: {e:?}");
-                        WIFI_CONTROLLER_ACTIVE.store(false, Ordering::Release);
-                        Timer::after(Duration::from_millis(wifi_reconnect_time)).await;
-                        continue;
-                    }
-
+            match restart_controller(&context.wifi, &configuration, power_save) {
+                Ok(new_controller) => {
                     controller_slot = Some(new_controller);
-                    stopped = false;
+                    backoff.reset();
                     log::info!("WIFI controller restarted.");
+                    events::publish(WmEvent::RadioStarted);
+
+                    if command == WmCommand::StartPortal {
+                        pending_commands.push_front(command);
+                    }
                 }
                 Err(e) => {
-                    log::info!("Failed to recreate wifi controller: {e:?}");
-                    WIFI_CONTROLLER_ACTIVE.store(false, Ordering::Release);
-                    Timer::after(Duration::from_millis(wifi_reconnect_time)).await;
-                    continue;
+                    log::info!("Failed to restart wifi controller: {e:?}");
+                    Timer::after(backoff.next_delay()).await;
                 }
             }
-        }
 
-        let controller = controller_slot
-            .as_mut()
-            .expect("Internal error: controller slot missing while running connection loop");
+            continue;
+        };
 
-        match controller.connect_async().await {
-            Ok(_) => {
-                if let Some(ref sig) = wifi_conn_signal {
-                    sig.signal(true);
-                }
-                log::info!("Wifi connected!");
-                let mut stop_requested = false;
+        let command = match pending_commands.pop_front() {
+            Some(command) => command,
+            None => {
+                keep_connected(
+                    controller,
+                    &commands,
+                    &mut backoff,
+                    &mut disconnection,
+                    context.settings.portal_fallback,
+                )
+                .await
+            }
+        };
 
-                'connected: loop {
-                    let res = embassy_futures::select::select(
-                        controller.wait_for_disconnect_async(),
-                        stop_signal.wait(),
-                    )
-                    .await;
+        match command {
+            WmCommand::StopRadio => {
+                _ = controller.disconnect_async().await;
+                controller_slot = None;
+                disconnection.reset();
+                WIFI_CONTROLLER_ACTIVE.store(false, Ordering::Release);
+                log::info!("WIFI controller stopped.");
+                events::publish(WmEvent::RadioStopped);
+            }
+            WmCommand::StartPortal => {
+                set_power_save(controller, esp_radio::wifi::PowerSaveMode::None);
+                let res = runtime_portal(
+                    &mut context,
+                    controller,
+                    &configuration,
+                    &ip_config,
+                    &commands,
+                    &mut pending_commands,
+                )
+                .await;
 
-                    match res {
-                        Either::First(ev) => {
-                            if let Ok(info) = ev {
-                                log::info!("Wifi disconnected: {:?}", info);
-                            }
-                            if let Some(ref sig) = wifi_conn_signal {
-                                sig.signal(false);
-                            }
-                            Timer::after(Duration::from_millis(wifi_reconnect_time)).await;
-                            break 'connected;
-                        }
+                match res {
+                    Ok(PortalEnd::Connected(network)) => match network.to_configuration() {
+                        Ok(network_configuration) => {
+                            let network_configuration = with_listen_interval(
+                                network_configuration,
+                                context.settings.listen_interval,
+                            );
+                            log::info!("Switching to new network: {}", network.ssid);
+                            _ = controller.disconnect_async().await;
+                            configuration = network_configuration;
+                            ip_config = network.to_ip_config(&context.settings.ssid);
+                            context.sta_stack.set_config_v4(ip_config.clone());
 
-                        Either::Second(val) => {
-                            if val {
-                                _ = controller.disconnect_async().await;
-                                if let Some(ref sig) = wifi_conn_signal {
-                                    sig.signal(false);
-                                }
-                                stop_requested = true;
-                                break 'connected;
+                            if context.settings.esp_restart_after_connection {
+                                log::info!("Wifimanager reset after succesfull connection...");
+                                Timer::after_millis(1000).await;
+                                esp_hal::system::software_reset();
                             }
                         }
+                        Err(e) => {
+                            log::error!("Invalid configuration of new network: {e:?}");
+                            context.sta_stack.set_config_v4(ip_config.clone());
+                        }
+                    },
+                    // fallback countdown starts again after user closed the portal, after timeout
+                    // portal is reopened right away if station is still disconnected
+                    Ok(PortalEnd::Stopped) => disconnection.reset(),
+                    Ok(PortalEnd::TimedOut) => {}
+                    Err(e) => {
+                        log::error!("Setup portal failed: {e:?}");
+                        disconnection.reset();
                     }
                 }
 
-                if stop_requested {
-                    WIFI_CONTROLLER_ACTIVE.store(false, Ordering::Release);
-                    controller_slot.take();
-                    stopped = true;
-                    log::info!("WIFI controller stopped.");
+                if let Err(e) = controller.set_config(&configuration) {
+                    log::error!("Cannot restore station config after setup portal: {e:?}");
+                }
+                set_power_save(controller, power_save);
+            }
+            WmCommand::SetPowerSave(mode) => {
+                power_save = mode;
+                set_power_save(controller, power_save);
+            }
+            WmCommand::RestartRadio | WmCommand::StopPortal | WmCommand::Reconnect => {}
+        }
+    }
+}
+
+/// Time without connection and auth failures counted for `WmSettings::portal_fallback`.
+/// It's kept while setup portal runs, so timed out portal doesn't restart the countdown.
+#[derive(Default)]
+struct Disconnection {
+    since: Option<Instant>,
+    auth_failures: u8,
+}
+
+impl Disconnection {
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn auth_failed(&mut self) {
+        self.auth_failures = self.auth_failures.saturating_add(1);
+    }
+}
+
+/// Keeps station connected (reconnecting with `WmSettings::reconnect_policy` delays)
+/// until radio stop or setup portal is requested (or `WmSettings::portal_fallback` is triggered)
+async fn keep_connected(
+    controller: &mut WifiController<'static>,
+    commands: &WmCommands,
+    backoff: &mut utils::Backoff,
+    disconnection: &mut Disconnection,
+    fallback: Option<PortalFallback>,
+) -> WmCommand {
+    loop {
+        if controller.is_connected().unwrap_or(false) {
+            disconnection.reset();
+        } else {
+            let disconnected_for = disconnection
+                .since
+                .get_or_insert_with(Instant::now)
+                .elapsed()
+                .as_millis();
+            let auth_failures = disconnection.auth_failures;
+
+            if fallback.is_some_and(|f| f.triggered(disconnected_for, auth_failures)) {
+                log::info!(
+                    "Cannot reconnect for {disconnected_for}ms ({auth_failures} auth failures), starting setup portal"
+                );
+                return WmCommand::StartPortal;
+            }
+
+            match embassy_futures::select::select(controller.connect_async(), commands.receive())
+                .await
+            {
+                Either::First(Ok(_)) => {
+                    log::info!("Wifi connected!");
+                    backoff.reset();
+                    disconnection.reset();
+                    events::publish(connected_event(controller));
+                }
+                Either::First(Err(e)) => {
+                    log::info!("Failed to connect to wifi: {e:?}");
+                    if ConnFailReason::from(&e) == ConnFailReason::AuthFailed {
+                        disconnection.auth_failed();
+                    }
+
+                    Timer::after(backoff.next_delay()).await;
                     continue;
                 }
+                Either::Second(
+                    command @ (WmCommand::StopRadio
+                    | WmCommand::StartPortal
+                    | WmCommand::SetPowerSave(_)),
+                ) => return command,
+                Either::Second(_) => continue,
             }
+        }
 
-            Err(e) => {
-                if let Some(ref sig) = wifi_conn_signal {
-                    sig.signal(false);
+        match embassy_futures::select::select(
+            controller.wait_for_disconnect_async(),
+            commands.receive(),
+        )
+        .await
+        {
+            Either::First(ev) => {
+                let reason = match ev {
+                    Ok(info) => {
+                        log::info!("Wifi disconnected: {:?}", info);
+                        ConnFailReason::from_reason_code(info.reason as u16)
+                    }
+                    Err(ref e) => ConnFailReason::from(e),
+                };
+                if reason == ConnFailReason::AuthFailed {
+                    disconnection.auth_failed();
                 }
-                log::info!("Failed to connect to wifi: {e:?}");
-                Timer::after(Duration::from_millis(wifi_reconnect_time)).await;
+
+                events::publish(WmEvent::Disconnected { reason });
+                Timer::after(backoff.next_delay()).await;
+            }
+            Either::Second(
+                command @ (WmCommand::StopRadio
+                | WmCommand::StartPortal
+                | WmCommand::SetPowerSave(_)),
+            ) => return command,
+            Either::Second(WmCommand::Reconnect) => {
+                log::info!("Reconnecting to wifi..");
+                _ = controller.disconnect_async().await;
+            }
+            Either::Second(_) => {}
+        }
+    }
+}
+
+/// Recreates wifi controller stopped by `WmReturn::stop_radio`
+fn restart_controller(
+    wifi: &WIFI<'static>,
+    configuration: &esp_radio::wifi::Config,
+    power_save: esp_radio::wifi::PowerSaveMode,
+) -> Result<WifiController<'static>> {
+    if WIFI_CONTROLLER_ACTIVE
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        log::info!("Skipped wifi restart because another controller owner is active.");
+        return Err(WmError::ControllerAlreadyActive);
+    }
+
+    let res = new_controller(wifi, configuration, power_save);
+    if res.is_err() {
+        WIFI_CONTROLLER_ACTIVE.store(false, Ordering::Release);
+    }
+
+    res
+}
+
+/// Creates controller from copy of `wifi`. Caller has to make sure previous controller
+/// was dropped (see `WIFI_CONTROLLER_ACTIVE`).
+fn new_controller(
+    wifi: &WIFI<'static>,
+    configuration: &esp_radio::wifi::Config,
+    power_save: esp_radio::wifi::PowerSaveMode,
+) -> Result<WifiController<'static>> {
+    let (mut controller, _) =
+        esp_radio::wifi::new(unsafe { wifi.clone_unchecked() }, Default::default())?;
+
+    controller.set_power_saving(power_save)?;
+    controller.set_config(configuration)?;
+    Ok(controller)
+}
+
+fn set_power_save(
+    controller: &mut WifiController<'static>,
+    power_save: esp_radio::wifi::PowerSaveMode,
+) {
+    match controller.set_power_saving(power_save) {
+        Ok(()) => log::info!("Wifi power save mode: {power_save:?}"),
+        Err(e) => log::warn!("Cannot set wifi power save mode {power_save:?}: {e:?}"),
+    }
+}
+
+/// Applies `WmSettings::listen_interval` to station configuration
+fn with_listen_interval(
+    configuration: esp_radio::wifi::Config,
+    listen_interval: Option<u16>,
+) -> esp_radio::wifi::Config {
+    let Some(listen_interval) = listen_interval else {
+        return configuration;
+    };
+
+    match configuration {
+        esp_radio::wifi::Config::Station(station) => {
+            esp_radio::wifi::Config::Station(station.with_listen_interval(listen_interval))
+        }
+        esp_radio::wifi::Config::EapStation(station) => {
+            esp_radio::wifi::Config::EapStation(station.with_listen_interval(listen_interval))
+        }
+        configuration => configuration,
+    }
+}
+
+/// Dhcp lease renewed with different address doesn't take station config down and
+/// embassy-net has no signal for config change, so while ip is held address is also
+/// compared after this time (in ms). It's the only periodic wakeup of ip monitor.
+const IP_CHANGE_CHECK_INTERVAL: u64 = 30000;
+
+/// Publishes station ip changes after `init_wm` returned and forces reassociation
+/// if link is up without ip address for `WmSettings::dhcp_timeout`
+#[embassy_executor::task]
+async fn ip_monitor_task(
+    sta_stack: Stack<'static>,
+    ip_address: Ipv4Addr,
+    dhcp_timeout: Option<u64>,
+    commands: Rc<WmCommands>,
+) {
+    let mut current_ip = Some(ip_address);
+
+    loop {
+        let ip = sta_stack.config_v4().map(|config| config.address.address());
+        if ip != current_ip {
+            match (current_ip, ip) {
+                (Some(old), Some(new)) => events::publish(WmEvent::IpChanged { old, new }),
+                (Some(_), None) => events::publish(WmEvent::IpLost),
+                (None, Some(ip)) => events::publish(WmEvent::GotIp { ip }),
+                (None, None) => {}
+            }
+
+            current_ip = ip;
+        }
+
+        if current_ip.is_some() {
+            embassy_futures::select::select(
+                sta_stack.wait_config_down(),
+                Timer::after_millis(IP_CHANGE_CHECK_INTERVAL),
+            )
+            .await;
+            continue;
+        }
+
+        if !sta_stack.is_link_up() {
+            embassy_futures::select::select(sta_stack.wait_config_up(), sta_stack.wait_link_up())
+                .await;
+            continue;
+        }
+
+        // link is up without ip address
+        let no_ip_timeout = async {
+            match dhcp_timeout {
+                Some(timeout) => Timer::after_millis(timeout).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        let res = embassy_futures::select::select3(
+            sta_stack.wait_config_up(),
+            sta_stack.wait_link_down(),
+            no_ip_timeout,
+        )
+        .await;
+        if let Either3::Third(()) = res {
+            log::warn!("No ip address after link up, reassociating..");
+            if commands.try_send(WmCommand::Reconnect).is_err() {
+                log::warn!("Command queue is full, reconnect skipped");
             }
         }
     }
@@ -0,0 +1,335 @@
+use crate::{
+    structs::AutoSetupSettings, Nvs, Result, WmError, WIFI_NVS_KEY, WIFI_NVS_QUARANTINE_KEY,
+};
+use alloc::vec::Vec;
+use esp_radio::wifi::{AccessPointInfo, AuthMethod, ScanConfig, WifiController};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Max number of networks kept in flash, lowest ranked ones are dropped first
+pub(crate) const MAX_SAVED_NETWORKS: usize = 5;
+
+/// Version of the format stored under `WIFI_NVS_KEY`:
+/// - 0: single `AutoSetupSettings` object (or networks list without version field)
+/// - 1: `SavedNetworks`
+///
+/// When bumping it, add migration from previous version to `SavedNetworks::parse`.
+pub(crate) const SAVED_NETWORKS_VERSION: u64 = 1;
+
+/// Access point found by wifi scan
+pub(crate) struct ScannedAp<'a> {
+    pub ssid: &'a str,
+    pub rssi: i8,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub auth: Option<AuthMethod>,
+}
+
+impl<'a> From<&'a AccessPointInfo> for ScannedAp<'a> {
+    fn from(ap: &'a AccessPointInfo) -> Self {
+        Self {
+            ssid: ap.ssid.as_str(),
+            rssi: ap.signal_strength,
+            bssid: ap.bssid,
+            channel: ap.channel,
+            auth: ap.auth_method,
+        }
+    }
+}
+
+/// Results of `scan`
+pub(crate) struct ScanResults {
+    /// Access points found by normal scan
+    pub aps: Vec<AccessPointInfo>,
+
+    /// Access points of hidden saved networks found by directed probes.
+    /// They aren't listed in setup portal, so hidden ssids aren't exposed to its clients.
+    pub hidden: Vec<AccessPointInfo>,
+}
+
+impl ScanResults {
+    /// All found access points (hidden ones included)
+    pub fn all(&self) -> impl Iterator<Item = ScannedAp<'_>> {
+        self.aps
+            .iter()
+            .chain(self.hidden.iter())
+            .map(ScannedAp::from)
+    }
+}
+
+/// Scans for access points. Hidden saved networks don't show up in normal scan,
+/// so they are searched with directed probe.
+pub(crate) async fn scan(
+    controller: &mut WifiController<'static>,
+    saved_networks: &SavedNetworks,
+) -> Result<ScanResults> {
+    let aps = controller.scan_async(&Default::default()).await?;
+    let mut hidden = Vec::new();
+    for network in saved_networks.networks.iter().filter(|n| n.hidden) {
+        let config = ScanConfig::default()
+            .with_ssid(&network.ssid)
+            .with_show_hidden(true);
+
+        match controller.scan_async(&config).await {
+            Ok(found) => hidden.extend(found.into_iter().filter(|ap| ap.ssid == network.ssid)),
+            Err(e) => log::warn!("Directed probe for {} failed: {e:?}", network.ssid),
+        }
+    }
+
+    Ok(ScanResults { aps, hidden })
+}
+
+/// List of known networks stored under `WIFI_NVS_KEY`
+#[derive(Debug, Serialize, Clone)]
+pub(crate) struct SavedNetworks {
+    pub version: u64,
+
+    /// Incremented on every successful connection.
+    /// There is no wall clock at boot, so it's used as `last_success` timestamp.
+    pub counter: u32,
+
+    /// Networks in the order they should be tried (see `SavedNetworks::sort`)
+    pub networks: Vec<AutoSetupSettings>,
+}
+
+impl Default for SavedNetworks {
+    fn default() -> Self {
+        Self {
+            version: SAVED_NETWORKS_VERSION,
+            counter: 0,
+            networks: Vec::new(),
+        }
+    }
+}
+
+impl SavedNetworks {
+    /// Reads saved networks from nvs.
+    ///
+    /// Unreadable data is moved to `WIFI_NVS_QUARANTINE_KEY` and empty list is returned,
+    /// so wifimanager can fall back to the setup portal.
+    pub async fn load(nvs: Option<&Nvs>) -> Self {
+        let Some(nvs) = nvs else {
+            return Self::default();
+        };
+
+        let wifi_setup = match nvs.get_string(WIFI_NVS_KEY).await {
+            Ok(res) => res,
+            Err(WmError::DecryptionError) => {
+                log::error!("Cannot decrypt saved networks, starting without them!");
+                return Self::default();
+            }
+            Err(_) => return Self::default(),
+        };
+
+        let (mut saved, migrated) = match Self::parse(&wifi_setup) {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!(
+                    "Saved networks are corrupted ({e:?}), moving them to {WIFI_NVS_QUARANTINE_KEY}"
+                );
+
+                _ = nvs.delete(WIFI_NVS_QUARANTINE_KEY).await;
+                if let Err(e) = nvs.set_string(WIFI_NVS_QUARANTINE_KEY, &wifi_setup).await {
+                    log::warn!("Failed to quarantine saved networks: {e:?}");
+                }
+                _ = nvs.delete(WIFI_NVS_KEY).await;
+
+                return Self::default();
+            }
+        };
+
+        saved.sort();
+        if migrated {
+            if let Err(e) = saved.save(nvs).await {
+                log::warn!("Failed to save migrated networks: {e:?}");
+            }
+        }
+
+        saved
+    }
+
+    /// Parses (and migrates to current version) saved networks string.
+    /// Returns true if data was changed during parsing and should be saved again.
+    fn parse(wifi_setup: &str) -> Result<(Self, bool)> {
+        let mut value: Value = serde_json::from_str(wifi_setup)?;
+        if !value.is_object() {
+            return Err(WmError::SerdeError(serde::de::Error::custom(
+                "saved networks should be an object",
+            )));
+        }
+
+        let mut version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+        let mut migrated = false;
+
+        if version == 0 {
+            if value.get("networks").is_none() {
+                value = serde_json::json!({ "networks": [value] });
+            }
+
+            log::info!("Migrating saved networks from version 0");
+            version = 1;
+            migrated = true;
+        }
+
+        if version > SAVED_NETWORKS_VERSION {
+            log::warn!("Saved networks version {version} is newer than supported one!");
+        }
+
+        let counter = value.get("counter").and_then(Value::as_u64).unwrap_or(0);
+        let Some(entries) = value.get_mut("networks").and_then(Value::as_array_mut) else {
+            return Err(WmError::SerdeError(serde::de::Error::custom(
+                "missing networks list",
+            )));
+        };
+
+        let mut networks = Vec::with_capacity(entries.len());
+        for entry in entries.iter_mut() {
+            match serde_json::from_value::<AutoSetupSettings>(entry.take()) {
+                Ok(network) => networks.push(network),
+                Err(e) => {
+                    log::warn!("Skipping unreadable saved network: {e:?}");
+                    migrated = true;
+                }
+            }
+        }
+
+        Ok((
+            Self {
+                version: SAVED_NETWORKS_VERSION,
+                counter: counter as u32,
+                networks,
+            },
+            migrated,
+        ))
+    }
+
+    /// Saves networks under `WIFI_NVS_KEY`. Whole list is stored in single nvs entry,
+    /// so lowest ranked networks are dropped if it doesn't fit there.
+    pub async fn save(&mut self, nvs: &Nvs) -> Result<()> {
+        let mut saved_str = serde_json::to_string(self)?;
+        while !crate::nvs::string_fits(saved_str.len()) && self.networks.len() > 1 {
+            let dropped = self.networks.pop();
+            log::warn!(
+                "Saved networks don't fit nvs entry, dropping: {:?}",
+                dropped.map(|n| n.ssid)
+            );
+            saved_str = serde_json::to_string(self)?;
+        }
+
+        _ = nvs.delete(WIFI_NVS_KEY).await;
+        nvs.set_string(WIFI_NVS_KEY, &saved_str).await?;
+        Ok(())
+    }
+
+    /// Adds new network or updates existing one with the same ssid.
+    /// If list is full, lowest ranked existing network is removed to make room for new one.
+    pub fn upsert(&mut self, mut network: AutoSetupSettings) {
+        if let Some(existing) = self.networks.iter_mut().find(|n| n.ssid == network.ssid) {
+            network.last_success = existing.last_success;
+            *existing = network;
+        } else {
+            // `last_success` is managed by `mark_success`, value sent by client is ignored
+            network.last_success = 0;
+
+            self.sort();
+            self.networks.truncate(MAX_SAVED_NETWORKS - 1);
+            self.networks.push(network);
+        }
+
+        self.sort();
+    }
+
+    /// Updates `last_success` and learned ap (bssid and channel) of connected network.
+    /// Returns true if anything changed (and list should be saved).
+    pub fn mark_success(&mut self, ssid: &str, ap: Option<([u8; 6], u8)>) -> bool {
+        let counter = self.counter;
+        let Some(network) = self.networks.iter_mut().find(|n| n.ssid == ssid) else {
+            return false;
+        };
+
+        let mut changed = false;
+        if let Some((bssid, channel)) = ap.filter(|_| !network.pinned) {
+            if (network.bssid, network.channel) != (Some(bssid), Some(channel)) {
+                network.bssid = Some(bssid);
+                network.channel = Some(channel);
+                changed = true;
+            }
+        }
+
+        if counter == 0 || network.last_success != counter {
+            self.counter = counter.wrapping_add(1).max(1);
+            network.last_success = self.counter;
+            changed = true;
+        }
+
+        self.sort();
+        changed
+    }
+
+    /// Returns first network (in `SavedNetworks::sort` order) previously connected to known ap,
+    /// that can be connected to without scan
+    pub fn fast_connect_candidate(&self) -> Option<&AutoSetupSettings> {
+        self.networks
+            .iter()
+            .find(|n| n.bssid.is_some() && n.last_success > 0)
+    }
+
+    /// Returns saved networks present in scan results, by priority first.
+    /// Networks with the same priority are ordered by signal strength.
+    /// Access points with weaker security than required by network are skipped.
+    ///
+    /// Returned networks (if not pinned) have bssid and channel of the strongest ap set.
+    pub fn visible<'a>(
+        &self,
+        aps: impl IntoIterator<Item = ScannedAp<'a>>,
+    ) -> Vec<AutoSetupSettings> {
+        let mut visible: Vec<(ScannedAp<'a>, &AutoSetupSettings)> = Vec::new();
+        for ap in aps {
+            let Some(network) = self.networks.iter().find(|n| n.ssid == ap.ssid) else {
+                continue;
+            };
+
+            if !network.accepts_auth(ap.auth) {
+                log::warn!(
+                    "Skipping {} ap with too weak security: {:?}",
+                    ap.ssid,
+                    ap.auth
+                );
+                continue;
+            }
+
+            match visible.iter_mut().find(|(_, n)| n.ssid == ap.ssid) {
+                Some((best, _)) if best.rssi < ap.rssi => *best = ap,
+                Some(_) => {}
+                None => visible.push((ap, network)),
+            }
+        }
+
+        visible.sort_by(|a, b| {
+            b.1.priority
+                .cmp(&a.1.priority)
+                .then(b.0.rssi.cmp(&a.0.rssi))
+        });
+        visible
+            .into_iter()
+            .map(|(ap, network)| {
+                let mut network = network.clone();
+                if !network.pinned {
+                    network.bssid = Some(ap.bssid);
+                    network.channel = Some(ap.channel);
+                }
+
+                network
+            })
+            .collect()
+    }
+
+    /// Sorts networks by priority (highest first), then by last successful connection
+    pub fn sort(&mut self) {
+        self.networks.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then(b.last_success.cmp(&a.last_success))
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use alloc::rc::Rc;
+use alloc::{rc::Rc, string::String};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embedded_storage::ReadStorage;
 use esp_nvs::{Get, Key, Set};
@@ -9,6 +9,9 @@ const PART_OFFSET: u32 = 0x8000;
 const PART_SIZE: u32 = 0xc00;
 
 const WIFIMANAGER_NAMESPACE: Key = Key::from_str("wifimanager");
+
+/// Max length of nvs string value (nul terminator excluded)
+const MAX_STRING_LEN: usize = 4000 - 1;
 static NVS_INSTANCES: AtomicU8 = AtomicU8::new(0);
 
 pub struct Nvs {
@@ -16,6 +19,18 @@ pub struct Nvs {
 
     offset: usize,
     size: usize,
+
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<[u8; 32]>,
+}
+
+/// Checks if string of given length fits single nvs entry after it's encoded by
+/// `Nvs::set_string` (encrypted values are hex encoded, so they take more than twice the size)
+pub(crate) const fn string_fits(len: usize) -> bool {
+    #[cfg(feature = "encryption")]
+    let len = crate::crypto::envelope_len(len);
+
+    len <= MAX_STRING_LEN
 }
 
 impl Nvs {
@@ -53,6 +68,9 @@ impl Nvs {
 
             offset: flash_offset,
             size: flash_size,
+
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
         })
     }
 
@@ -118,6 +136,85 @@ impl Nvs {
         Ok(d.set(&WIFIMANAGER_NAMESPACE, &Key::from_str(key), value)?)
     }
 
+    /// Enables encryption of values stored with `set_string` (wifi credentials included).
+    ///
+    /// Key is derived from eFuse MAC and `secret`. MAC alone isn't secret (it's broadcasted
+    /// over the air), so with empty `secret` values are only obfuscated, not confidential.
+    /// Pass some device-unique secret if you can (e.g. from eFuse key block).
+    ///
+    /// Existing plaintext values are encrypted when they are read. After first value is stored
+    /// encrypted, plaintext values are rejected, so read values written before encryption
+    /// was enabled before writing new ones.
+    ///
+    /// Must be set on nvs before cloning it.
+    #[cfg(feature = "encryption")]
+    pub fn enable_encryption(&mut self, secret: &[u8]) {
+        let mac = esp_hal::efuse::base_mac_address();
+        self.encryption_key = Some(crate::crypto::derive_key(mac.as_bytes(), secret));
+    }
+
+    /// Reads string value, decrypting it if it was stored encrypted.
+    ///
+    /// With encryption enabled, plaintext value is encrypted on first read. Once any value
+    /// was stored encrypted, plaintext values are rejected with `WmError::DecryptionError`
+    /// (so encrypted value cannot be silently replaced by plaintext one).
+    pub async fn get_string(&self, key: &str) -> crate::Result<String> {
+        let value: String = self.get(key).await?;
+
+        #[cfg(feature = "encryption")]
+        {
+            let plaintext_allowed =
+                self.encryption_key.is_some() && !self.encryption_migrated().await;
+            match crate::crypto::decode(self.encryption_key.as_ref(), plaintext_allowed, key, value)
+            {
+                Ok(crate::crypto::Decoded::Value(value)) => Ok(value),
+                Ok(crate::crypto::Decoded::Unencrypted(value)) => {
+                    log::info!("Encrypting nvs value \"{key}\"");
+                    _ = self.delete(key).await;
+                    self.set_string(key, &value).await?;
+                    Ok(value)
+                }
+                Err(e) => {
+                    log::error!("Cannot read nvs value \"{key}\": {e:?}");
+                    Err(crate::WmError::DecryptionError)
+                }
+            }
+        }
+
+        #[cfg(not(feature = "encryption"))]
+        Ok(value)
+    }
+
+    /// True if any value was stored encrypted (plaintext values aren't accepted anymore)
+    #[cfg(feature = "encryption")]
+    async fn encryption_migrated(&self) -> bool {
+        self.get::<u8>(crate::ENCRYPTION_MARKER_NVS_KEY)
+            .await
+            .is_ok_and(|marker| marker == 1)
+    }
+
+    /// Writes string value, encrypting it if encryption is enabled
+    pub async fn set_string(&self, key: &str, value: &str) -> crate::Result<()> {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption_key) = self.encryption_key.as_ref() {
+            let mut rng = esp_hal::rng::Rng::new();
+            let mut nonce = [0; 12];
+            for chunk in nonce.chunks_mut(4) {
+                chunk.copy_from_slice(&rng.random().to_le_bytes());
+            }
+
+            let envelope = crate::crypto::seal(encryption_key, nonce, key, value.as_bytes());
+            self.set(key, envelope.as_str()).await?;
+
+            if !self.encryption_migrated().await {
+                self.set(crate::ENCRYPTION_MARKER_NVS_KEY, 1u8).await?;
+            }
+            return Ok(());
+        }
+
+        self.set(key, value).await
+    }
+
     pub async fn delete(&self, key: &str) -> crate::Result<()> {
         let mut d = self.inner.lock().await;
         Ok(d.delete(&WIFIMANAGER_NAMESPACE, &Key::from_str(key))?)
@@ -138,6 +235,9 @@ impl Clone for Nvs {
             inner: self.inner.clone(),
             offset: self.offset,
             size: self.size,
+
+            #[cfg(feature = "encryption")]
+            encryption_key: self.encryption_key,
         }
     }
 }
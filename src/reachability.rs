@@ -0,0 +1,262 @@
+use crate::{
+    events::{self, WmEvent},
+    structs::{WmCommand, WmCommands},
+};
+use alloc::{format, rc::Rc};
+use core::{cell::Cell, net::Ipv4Addr};
+use embassy_futures::select::select;
+use embassy_net::{
+    dns::DnsQueryType,
+    icmp::{
+        ping::{PingManager, PingParams},
+        PacketMetadata,
+    },
+    tcp::TcpSocket,
+    IpAddress, Stack,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::Write;
+
+/// Result of internet reachability check
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Connectivity {
+    /// Not checked yet (or station has no ip address)
+    #[default]
+    Unknown,
+
+    /// Probe succeeded
+    Connected,
+
+    /// Probe failed or timed out
+    ConnectedNoInternet,
+
+    /// Http probe got unexpected response (login page or redirect)
+    CaptivePortalDetected,
+}
+
+/// How internet reachability is checked
+#[derive(Debug, Clone, Copy)]
+pub enum ReachabilityProbe {
+    /// Icmp echo to default gateway (checks only local network)
+    Gateway,
+
+    /// Dns lookup of given host
+    Dns(&'static str),
+
+    /// Http `GET` of `http://host[:port]/path` url. Response with `expected_status` means
+    /// internet is reachable, any other response means captive portal.
+    Http {
+        url: &'static str,
+        expected_status: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReachabilityCheck {
+    pub probe: ReachabilityProbe,
+
+    /// Time between checks (in ms)
+    pub interval: u64,
+
+    /// Timeout of single check (in ms)
+    pub timeout: u64,
+
+    /// Number of consecutive failed checks after which station reconnects
+    pub reconnect_after: Option<u8>,
+}
+
+impl Default for ReachabilityCheck {
+    fn default() -> Self {
+        Self {
+            probe: ReachabilityProbe::Http {
+                url: "http://connectivitycheck.gstatic.com/generate_204",
+                expected_status: 204,
+            },
+            interval: 60000,
+            timeout: 5000,
+            reconnect_after: None,
+        }
+    }
+}
+
+static CONNECTIVITY: BlockingMutex<CriticalSectionRawMutex, Cell<Connectivity>> =
+    BlockingMutex::new(Cell::new(Connectivity::Unknown));
+
+/// Result of last reachability check
+pub(crate) fn connectivity() -> Connectivity {
+    CONNECTIVITY.lock(Cell::get)
+}
+
+fn set_connectivity(connectivity: Connectivity) {
+    if CONNECTIVITY.lock(|c| c.replace(connectivity)) != connectivity {
+        events::publish(WmEvent::ConnectivityChanged { connectivity });
+    }
+}
+
+/// Checks reachability after station gets ip address and then every `ReachabilityCheck::interval`
+#[embassy_executor::task]
+pub(crate) async fn reachability_task(
+    stack: Stack<'static>,
+    check: ReachabilityCheck,
+    commands: Rc<WmCommands>,
+) {
+    let mut failures = 0u8;
+    loop {
+        if !stack.is_config_up() {
+            set_connectivity(Connectivity::Unknown);
+            failures = 0;
+            stack.wait_config_up().await;
+        }
+
+        let connectivity = with_timeout(
+            Duration::from_millis(check.timeout),
+            probe(stack, check.probe),
+        )
+        .await
+        .unwrap_or(Connectivity::ConnectedNoInternet);
+        set_connectivity(connectivity);
+
+        if connectivity == Connectivity::Connected {
+            failures = 0;
+        } else {
+            failures = failures.saturating_add(1);
+            if check.reconnect_after.is_some_and(|n| failures >= n) {
+                log::warn!("Reachability check failed {failures} times, reconnecting..");
+                if commands.try_send(WmCommand::Reconnect).is_err() {
+                    log::warn!("Command queue is full, reconnect skipped");
+                }
+                failures = 0;
+            }
+        }
+
+        // next check is done right after station gets ip address again
+        select(
+            Timer::after_millis(check.interval),
+            stack.wait_config_down(),
+        )
+        .await;
+    }
+}
+
+async fn probe(stack: Stack<'static>, probe: ReachabilityProbe) -> Connectivity {
+    let res = match probe {
+        ReachabilityProbe::Gateway => probe_gateway(stack).await,
+        ReachabilityProbe::Dns(host) => resolve(stack, host).await.map(|_| Connectivity::Connected),
+        ReachabilityProbe::Http {
+            url,
+            expected_status,
+        } => probe_http(stack, url, expected_status).await,
+    };
+
+    res.unwrap_or(Connectivity::ConnectedNoInternet)
+}
+
+async fn probe_gateway(stack: Stack<'static>) -> Option<Connectivity> {
+    let gateway = stack.config_v4()?.gateway?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY];
+    let mut rx_buffer = [0; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY];
+    let mut tx_buffer = [0; 128];
+    let mut ping_manager = PingManager::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut params = PingParams::new(gateway);
+    params.set_count(1);
+
+    match ping_manager.ping(&mut params).await {
+        Ok(_) => Some(Connectivity::Connected),
+        Err(e) => {
+            log::debug!("Gateway ping failed: {e:?}");
+            None
+        }
+    }
+}
+
+async fn probe_http(
+    stack: Stack<'static>,
+    url: &str,
+    expected_status: u16,
+) -> Option<Connectivity> {
+    let Some((host, port, path)) = parse_http_url(url) else {
+        log::warn!("Invalid reachability probe url: {url}");
+        return None;
+    };
+    let addr = resolve(stack, host).await?;
+
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 256];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    if let Err(e) = socket.connect((addr, port)).await {
+        log::debug!("Reachability probe connect failed: {e:?}");
+        return None;
+    }
+
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    if socket.write_all(request.as_bytes()).await.is_err() {
+        socket.abort();
+        return None;
+    }
+    _ = socket.flush().await;
+
+    // only status line is needed
+    let mut response = [0; 64];
+    let mut total_read = 0;
+    while total_read < response.len() && !response[..total_read].contains(&b'\n') {
+        match socket.read(&mut response[total_read..]).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => total_read += n,
+        }
+    }
+
+    socket.close();
+    socket.abort();
+
+    let status = parse_status(&response[..total_read])?;
+    if status == expected_status {
+        Some(Connectivity::Connected)
+    } else {
+        log::info!("Reachability probe got {status} status, captive portal detected");
+        Some(Connectivity::CaptivePortalDetected)
+    }
+}
+
+async fn resolve(stack: Stack<'static>, host: &str) -> Option<IpAddress> {
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Some(IpAddress::Ipv4(ip));
+    }
+
+    match stack.dns_query(host, DnsQueryType::A).await {
+        Ok(addrs) => addrs.first().copied(),
+        Err(e) => {
+            log::debug!("Dns query of {host} failed: {e:?}");
+            None
+        }
+    }
+}
+
+/// Splits `http://host[:port]/path` url into host, port and path
+fn parse_http_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?, path)),
+        None => Some((authority, 80, path)),
+    }
+}
+
+/// Parses status code from `HTTP/1.1 204 No Content` line
+fn parse_status(response: &[u8]) -> Option<u16> {
+    let line = core::str::from_utf8(response).ok()?.lines().next()?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
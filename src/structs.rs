@@ -1,22 +1,50 @@
-use crate::get_efuse_mac;
-use alloc::{rc::Rc, string::String};
+#[cfg(feature = "reachability")]
+use crate::ReachabilityCheck;
+use crate::{
+    events::WmEventSubscriber,
+    get_efuse_mac,
+    validation::{self, ValidationError},
+    Nvs,
+};
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    net::Ipv4Addr,
+    str::FromStr,
+};
 use embassy_executor::SpawnError;
-use embassy_net::Stack;
+use embassy_net::{ConfigV4, DhcpConfig, Stack};
 use embassy_sync::{
-    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    blocking_mutex::{
+        raw::{CriticalSectionRawMutex, NoopRawMutex},
+        Mutex as BlockingMutex,
+    },
+    channel::Channel,
     mutex::Mutex,
-    pubsub::PubSubChannel,
+    pubsub::{PubSubChannel, Subscriber},
     signal::Signal,
 };
-use esp_radio::wifi::{sta::StationConfig, Config, WifiError};
-use serde::{Deserialize, Serialize};
+use esp_hal::{
+    gpio::{Input, InputConfig, InputPin, Pull},
+    peripherals::WIFI,
+};
+use esp_radio::wifi::{
+    sta::{
+        EapStationConfig, ProtectedManagementFrame, ScanMethod, StationConfig, TtlsPhase2Method,
+    },
+    AuthMethod, Config, PowerSaveMode, WifiError,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub use esp_hal_wifimanager_common::ReconnectPolicy;
 
 pub type Result<T> = core::result::Result<T, WmError>;
 
 #[derive(Debug)]
 pub enum WmError {
-    /// TODO: add connection timeout (time after which init_wm returns WmTimeout error
-    WmTimeout,
+    /// Setup portal wasn't finished before `WmSettings::portal_timeout`.
+    /// Radio is released and its peripherals are returned, so `init_wm` can be called again.
+    WmTimeout(WmPeripherals),
 
     WifiControllerStartError,
     WifiError(WifiError),
@@ -25,9 +53,26 @@ pub enum WmError {
     NvsError(esp_nvs::error::Error),
     ControllerAlreadyActive,
 
+    /// Encrypted nvs value cannot be decrypted (wrong key or corrupted value)
+    DecryptionError,
+
     Other,
 }
 
+/// Radio peripherals returned with `WmError::WmTimeout`
+pub struct WmPeripherals {
+    pub wifi: WIFI<'static>,
+
+    #[cfg(feature = "ble")]
+    pub bt: esp_hal::peripherals::BT<'static>,
+}
+
+impl core::fmt::Debug for WmPeripherals {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WmPeripherals").finish_non_exhaustive()
+    }
+}
+
 impl From<esp_nvs::error::Error> for WmError {
     fn from(value: esp_nvs::error::Error) -> Self {
         Self::NvsError(value)
@@ -75,59 +120,588 @@ pub struct WmSettings {
     /// Max time WiFi will try to connect (in ms)
     pub wifi_conn_timeout: u64,
 
-    /// Delay on wifi reconnection after connection loss (in ms)
-    pub wifi_reconnect_time: u64,
+    /// Max time WiFi will try to connect to cached access point at boot, before falling back
+    /// to scan (in ms)
+    pub fast_connect_timeout: u64,
+
+    /// Delays between reconnect attempts after connection loss
+    pub reconnect_policy: ReconnectPolicy,
+
+    /// Station power save mode applied after `init_wm` connected (setup always runs without
+    /// power save). Can be changed at runtime with `WmReturn::set_power_save`.
+    pub power_save: PowerSaveMode,
+
+    /// Number of beacon intervals between station wakeups in `PowerSaveMode::Maximum`
+    /// (esp-idf default if `None`)
+    pub listen_interval: Option<u16>,
 
     /// WiFi scan inverval (in ms)
     pub wifi_scan_interval: u64,
 
+    /// Interval of retrying saved networks while waiting for wifi setup (in ms).
+    /// Only networks seen in the last wifi scan are retried.
+    pub saved_networks_retry_interval: Option<u64>,
+
+    /// Time without ip address after link up, after which station reassociates (in ms).
+    /// Checked only after `init_wm` returned.
+    pub dhcp_timeout: Option<u64>,
+
     /// Time after which esp will restart while waiting for wifi setup (in ms)
     pub esp_reset_timeout: Option<u64>,
 
+    /// Time after which setup portal is stopped and `init_wm` returns `WmError::WmTimeout` (in ms).
+    /// Radio is stopped then, so `init_wm` can be called again later.
+    pub portal_timeout: Option<u64>,
+
+    /// Reopens setup portal after `init_wm` returned, if saved network cannot be connected
+    pub portal_fallback: Option<PortalFallback>,
+
+    /// Internet reachability check done after station gets ip address (and periodically)
+    #[cfg(feature = "reachability")]
+    pub reachability: Option<ReachabilityCheck>,
+
     /// Indicates if esp should restart after succesfull first connection
     pub esp_restart_after_connection: bool,
 
-    /// Signal that will be sent when wifi peripheral sends StaDisconnected or WifiConnected
-    pub wifi_conn_signal: Option<Rc<Signal<CriticalSectionRawMutex, bool>>>,
+    /// Token required by factory reset http endpoint (`Authorization: Bearer <token>`)
+    /// and ble characteristic. Factory reset through them is disabled if `None`.
+    ///
+    /// Endpoint and characteristic are available only while setup portal is running,
+    /// use `WmReturn::factory_reset` after `init_wm` returned.
+    pub factory_reset_token: Option<String>,
+
+    /// Button that triggers factory reset if held at boot
+    pub factory_reset_gpio: Option<FactoryResetGpio>,
+
+    /// Additional (user) nvs keys removed on factory reset
+    pub factory_reset_keys: &'static [&'static str],
+
+    /// Forces setup portal (even if saved network is available) after quick consecutive resets
+    pub reset_portal_trigger: Option<ResetPortalTrigger>,
+
+    /// Additional fields rendered by default panel, stored in setup `data` object
+    pub provisioning_fields: &'static [ProvisioningField],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResetPortalTrigger {
+    /// Number of resets that starts setup portal (2 for double reset).
+    /// Values <= 1 force setup portal on every boot.
+    pub resets: u8,
+
+    /// Time after boot in which next reset is counted (in ms)
+    pub window: u64,
+}
+
+/// Conditions that reopen setup portal when station cannot reconnect (e.g. router password
+/// was changed). Station keeps retrying saved networks (every
+/// `WmSettings::saved_networks_retry_interval`) and portal stops when any network connects.
+#[derive(Debug, Clone, Copy)]
+pub struct PortalFallback {
+    /// Time without connection after which portal is started (in ms)
+    pub disconnected_time: Option<u64>,
+
+    /// Number of consecutive auth failures after which portal is started
+    pub auth_failures: Option<u8>,
+}
+
+impl PortalFallback {
+    pub(crate) fn triggered(&self, disconnected_for: u64, auth_failures: u8) -> bool {
+        self.disconnected_time
+            .is_some_and(|t| disconnected_for >= t)
+            || self.auth_failures.is_some_and(|n| auth_failures >= n)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FactoryResetGpio {
+    input: Rc<Input<'static>>,
+
+    /// Indicates if button pulls pin low when pressed
+    pub active_low: bool,
+
+    /// Time button needs to be held at boot (in ms)
+    pub hold_time: u64,
+}
+
+impl FactoryResetGpio {
+    /// Configures `pin` as input with internal pull-up if `active_low` is set
+    /// (pull-down otherwise)
+    pub fn new(pin: impl InputPin + 'static, active_low: bool, hold_time: u64) -> Self {
+        let pull = if active_low { Pull::Up } else { Pull::Down };
+
+        Self {
+            input: Rc::new(Input::new(pin, InputConfig::default().with_pull(pull))),
+            active_low,
+            hold_time,
+        }
+    }
+
+    /// True if button is currently pressed
+    pub(crate) fn pressed(&self) -> bool {
+        self.input.is_low() == self.active_low
+    }
 }
 
 impl core::fmt::Debug for WmSettings {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("WmSettings")
+        let mut debug = f.debug_struct("WmSettings");
+        debug
             .field("ssid", &self.ssid)
             .field("wifi_panel", &self.wifi_panel)
             .field("wifi_conn_timeout", &self.wifi_conn_timeout)
-            .field("wifi_reconnect_time", &self.wifi_reconnect_time)
+            .field("fast_connect_timeout", &self.fast_connect_timeout)
+            .field("reconnect_policy", &self.reconnect_policy)
+            .field("power_save", &self.power_save)
+            .field("listen_interval", &self.listen_interval)
             .field("wifi_scan_interval", &self.wifi_scan_interval)
+            .field(
+                "saved_networks_retry_interval",
+                &self.saved_networks_retry_interval,
+            )
+            .field("dhcp_timeout", &self.dhcp_timeout)
             .field("esp_reset_timeout", &self.esp_reset_timeout)
+            .field("portal_timeout", &self.portal_timeout)
+            .field("portal_fallback", &self.portal_fallback);
+
+        #[cfg(feature = "reachability")]
+        debug.field("reachability", &self.reachability);
+
+        debug
             .field(
                 "esp_restart_after_connection",
                 &self.esp_restart_after_connection,
             )
             .field(
-                "wifi_conn_signal",
-                &self.wifi_conn_signal.as_ref().map(|_| "Assigned"),
+                "factory_reset_token",
+                &self.factory_reset_token.as_ref().map(|_| "Assigned"),
             )
+            .field("factory_reset_gpio", &self.factory_reset_gpio)
+            .field("factory_reset_keys", &self.factory_reset_keys)
+            .field("reset_portal_trigger", &self.reset_portal_trigger)
+            .field("provisioning_fields", &self.provisioning_fields)
             .finish()
     }
 }
 
+/// Field of setup `data` object
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ProvisioningField {
+    /// Key in `data` object
+    pub key: &'static str,
+
+    /// Label shown in panel
+    pub label: &'static str,
+
+    #[serde(rename = "type")]
+    pub kind: FieldKind,
+
+    /// Value prefilled in panel
+    pub default: Option<&'static str>,
+
+    pub required: bool,
+
+    /// Min value for numbers, min length (in characters, like html `minlength`) for text fields
+    pub min: Option<i64>,
+
+    /// Max value for numbers, max length (in characters, like html `maxlength`) for text fields
+    pub max: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    Text,
+    Password,
+    Number,
+    Checkbox,
+}
+
+impl ProvisioningField {
+    pub const fn new(key: &'static str, label: &'static str, kind: FieldKind) -> Self {
+        Self {
+            key,
+            label,
+            kind,
+            default: None,
+            required: false,
+            min: None,
+            max: None,
+        }
+    }
+
+    /// Checks value of this field in `data` object
+    pub fn validate(&self, data: Option<&serde_json::Value>) -> bool {
+        let value = match data.and_then(|d| d.get(self.key)) {
+            None | Some(serde_json::Value::Null) => return !self.required,
+            Some(value) => value,
+        };
+
+        let in_range = |v: f64| {
+            self.min.is_none_or(|min| v >= min as f64) && self.max.is_none_or(|max| v <= max as f64)
+        };
+
+        match self.kind {
+            FieldKind::Text | FieldKind::Password => value
+                .as_str()
+                .is_some_and(|v| in_range(v.chars().count() as f64)),
+            FieldKind::Number => value.as_f64().is_some_and(in_range),
+            FieldKind::Checkbox => value.is_boolean(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct AutoSetupSettings {
     pub ssid: String,
     pub psk: String,
     pub data: Option<serde_json::Value>,
+
+    /// Networks with higher priority are tried first
+    #[serde(default)]
+    pub priority: u8,
+
+    /// `SavedNetworks::counter` value of last successful connection (0 - never connected)
+    #[serde(default)]
+    pub last_success: u32,
+
+    /// Static ipv4 configuration (dhcp is used if `None`)
+    #[serde(default)]
+    pub static_ip: Option<StaticIp>,
+
+    /// Bssid of access point, pinned by user or learned after connection (`aa:bb:cc:dd:ee:ff`).
+    /// Learned bssid and channel are used only for connection attempts at boot.
+    #[serde(default, with = "bssid_format")]
+    pub bssid: Option<[u8; 6]>,
+
+    /// Channel of access point, pinned by user or learned after connection
+    #[serde(default)]
+    pub channel: Option<u8>,
+
+    /// Indicates if bssid and channel were set by user (they aren't updated then)
+    #[serde(default)]
+    pub pinned: bool,
+
+    /// 802.1X credentials of enterprise network (`psk` is used as eap password)
+    #[serde(default)]
+    pub eap: Option<EapSettings>,
+
+    /// Network doesn't broadcast its ssid (it's searched with directed probe)
+    #[serde(default)]
+    pub hidden: bool,
+
+    /// Weakest accepted security of access point (any if `None`)
+    #[serde(default)]
+    pub auth: Option<MinAuthMode>,
+
+    /// Protected management frames policy
+    #[serde(default)]
+    pub pmf: PmfMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MinAuthMode {
+    Wpa2,
+
+    /// Wpa2/Wpa3 transition mode
+    Transition,
+
+    /// Wpa3-sae only
+    Wpa3,
+}
+
+impl MinAuthMode {
+    fn auth_method(self) -> AuthMethod {
+        match self {
+            MinAuthMode::Wpa2 => AuthMethod::Wpa2Personal,
+            MinAuthMode::Transition => AuthMethod::Wpa2Wpa3Personal,
+            MinAuthMode::Wpa3 => AuthMethod::Wpa3Personal,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PmfMode {
+    Disabled,
+
+    /// Pmf is used if access point supports it
+    #[default]
+    Capable,
+    Required,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct EapSettings {
+    #[serde(default)]
+    pub method: EapMethod,
+
+    /// Outer (anonymous) identity, username is used if not set
+    #[serde(default)]
+    pub identity: Option<String>,
+    pub username: String,
+
+    /// Pem encoded ca certificate of authentication server (server isn't verified if not set)
+    ///
+    /// NOTE: it's stored with other saved networks, so big certificate chains won't fit in nvs
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EapMethod {
+    #[default]
+    Peap,
+
+    /// Eap-ttls with mschapv2 inner authentication
+    Ttls,
+}
+
+impl EapSettings {
+    pub fn is_valid(&self) -> bool {
+        !self.username.is_empty()
+            && self
+                .ca_cert
+                .as_ref()
+                .is_none_or(|cert| cert.contains("-----BEGIN CERTIFICATE-----"))
+    }
+}
+
+/// Returns `'static` copy of nul terminated certificate (required by esp-radio).
+/// Every distinct certificate is copied once and reused, so connection retries and
+/// switching between saved networks don't leak memory.
+fn static_ca_cert(pem: &str) -> &'static [u8] {
+    static CERTS: BlockingMutex<CriticalSectionRawMutex, RefCell<Vec<&'static [u8]>>> =
+        BlockingMutex::new(RefCell::new(Vec::new()));
+
+    CERTS.lock(|certs| {
+        let mut certs = certs.borrow_mut();
+        if let Some(cert) = certs
+            .iter()
+            .find(|c| c.strip_suffix(&[0]) == Some(pem.as_bytes()))
+        {
+            return *cert;
+        }
+
+        let mut cert = Vec::with_capacity(pem.len() + 1);
+        cert.extend_from_slice(pem.as_bytes());
+        cert.push(0);
+
+        let cert: &'static [u8] = cert.leak();
+        certs.push(cert);
+        cert
+    })
+}
+
+mod bssid_format {
+    use alloc::string::String;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bssid: &Option<[u8; 6]>,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        match bssid {
+            Some(b) => serializer.collect_str(&format_args!(
+                "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                b[0], b[1], b[2], b[3], b[4], b[5]
+            )),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Option<[u8; 6]>, D::Error> {
+        let Some(bssid) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+
+        let mut bytes = [0; 6];
+        let mut parts = bssid.split([':', '-']);
+        for byte in bytes.iter_mut() {
+            *byte = parts
+                .next()
+                .and_then(|p| u8::from_str_radix(p, 16).ok())
+                .ok_or_else(|| serde::de::Error::custom("invalid bssid"))?;
+        }
+
+        if parts.next().is_some() {
+            return Err(serde::de::Error::custom("invalid bssid"));
+        }
+
+        Ok(Some(bytes))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct StaticIp {
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+
+    /// Dns servers (only first 3 are used)
+    #[serde(default)]
+    pub dns: Vec<Ipv4Addr>,
+}
+
+impl StaticIp {
+    pub fn is_valid(&self) -> bool {
+        self.prefix <= 32
+    }
+
+    pub fn to_config(&self) -> embassy_net::StaticConfigV4 {
+        embassy_net::StaticConfigV4 {
+            address: embassy_net::Ipv4Cidr::new(self.address, self.prefix.min(32)),
+            gateway: self.gateway,
+            dns_servers: self.dns.iter().take(3).copied().collect(),
+        }
+    }
 }
 
 impl AutoSetupSettings {
+    /// Station configuration used after connection. Learned bssid and channel aren't used
+    /// (only pinned ones), so station can roam between access points of the network.
     pub fn to_configuration(&self) -> Result<Config> {
-        Ok(Config::Station(self.to_station()?))
+        self.configuration(self.pinned)
+    }
+
+    /// Configuration of single connection attempt, locked to known (pinned or learned)
+    /// access point, so it can be connected to without scan
+    pub(crate) fn to_attempt_configuration(&self) -> Result<Config> {
+        self.configuration(true)
     }
 
     pub fn to_station(&self) -> Result<StationConfig> {
-        Ok(StationConfig::default()
+        self.station(self.pinned)
+    }
+
+    pub(crate) fn to_attempt_station(&self) -> Result<StationConfig> {
+        self.station(true)
+    }
+
+    fn configuration(&self, use_ap: bool) -> Result<Config> {
+        match self.eap {
+            Some(ref eap) => Ok(Config::EapStation(self.eap_station(eap, use_ap))),
+            None => Ok(Config::Station(self.station(use_ap)?)),
+        }
+    }
+
+    fn station(&self, use_ap: bool) -> Result<StationConfig> {
+        let mut station = StationConfig::default()
+            .with_ssid(self.ssid.clone())
+            .with_password(self.psk.clone())
+            .with_protected_management_frames(match self.pmf {
+                PmfMode::Disabled => ProtectedManagementFrame::Disabled,
+                PmfMode::Capable => ProtectedManagementFrame::Optional,
+                PmfMode::Required => ProtectedManagementFrame::Required,
+            });
+
+        if let Some(auth) = self.auth {
+            station = station.with_auth_method(auth.auth_method());
+        }
+
+        if self.hidden {
+            station = station.with_scan_method(ScanMethod::AllChannels);
+        }
+
+        if use_ap {
+            if let Some(bssid) = self.bssid {
+                station = station.with_bssid(bssid);
+            }
+
+            if let Some(channel) = self.channel {
+                station = station.with_channel(channel);
+            }
+        }
+
+        Ok(station)
+    }
+
+    /// Ipv4 config of station interface (dhcp with given hostname if static ip isn't set)
+    pub fn to_ip_config(&self, hostname: &str) -> ConfigV4 {
+        match self.static_ip {
+            Some(ref static_ip) => ConfigV4::Static(static_ip.to_config()),
+            None => {
+                let mut dhcp_config = DhcpConfig::default();
+                dhcp_config.hostname = heapless::String::from_str(hostname).ok();
+                ConfigV4::Dhcp(dhcp_config)
+            }
+        }
+    }
+
+    /// Checks if access point security isn't weaker than required `auth`.
+    /// Enterprise networks with `auth` set accept only enterprise access points.
+    pub fn accepts_auth(&self, auth: Option<AuthMethod>) -> bool {
+        let Some(min_auth) = self.auth else {
+            return true;
+        };
+
+        if self.eap.is_some() {
+            return auth == Some(AuthMethod::Wpa2Enterprise);
+        }
+
+        let Some(auth) = auth else {
+            return false;
+        };
+
+        match min_auth {
+            MinAuthMode::Wpa2 => matches!(
+                auth,
+                AuthMethod::Wpa2Personal
+                    | AuthMethod::WpaWpa2Personal
+                    | AuthMethod::Wpa2Wpa3Personal
+                    | AuthMethod::Wpa3Personal
+            ),
+            MinAuthMode::Transition => {
+                matches!(
+                    auth,
+                    AuthMethod::Wpa2Wpa3Personal | AuthMethod::Wpa3Personal
+                )
+            }
+            MinAuthMode::Wpa3 => auth == AuthMethod::Wpa3Personal,
+        }
+    }
+
+    /// Wpa3 requires pmf, so it cannot be disabled
+    pub fn is_security_valid(&self) -> bool {
+        !(self.auth == Some(MinAuthMode::Wpa3) && self.pmf == PmfMode::Disabled)
+    }
+
+    fn eap_station(&self, eap: &EapSettings, use_ap: bool) -> EapStationConfig {
+        let mut station = EapStationConfig::default()
             .with_ssid(self.ssid.clone())
-            .with_password(self.psk.clone()))
+            .with_identity(eap.identity.clone().unwrap_or_else(|| eap.username.clone()))
+            .with_username(eap.username.clone())
+            .with_password(self.psk.clone());
+
+        if eap.method == EapMethod::Ttls {
+            station = station.with_ttls_phase2_method(TtlsPhase2Method::MsChapV2);
+        }
+
+        if let Some(ref ca_cert) = eap.ca_cert {
+            station = station.with_ca_cert(static_ca_cert(ca_cert));
+        }
+
+        if use_ap {
+            if let Some(bssid) = self.bssid {
+                station = station.with_bssid(bssid);
+            }
+
+            if let Some(channel) = self.channel {
+                station = station.with_channel(channel);
+            }
+        }
+
+        if self.hidden {
+            station = station.with_scan_method(ScanMethod::AllChannels);
+        }
+
+        station
     }
 }
 
@@ -145,40 +719,144 @@ impl Default for WmSettings {
             #[cfg(feature = "custom_panel")]
             wifi_panel: "<h1>EMPTY PANEL</h1>",
 
-            wifi_reconnect_time: 1000,
+            reconnect_policy: ReconnectPolicy::default(),
+            power_save: PowerSaveMode::None,
+            listen_interval: None,
             wifi_conn_timeout: 15000,
+            fast_connect_timeout: 3000,
             wifi_scan_interval: 15000,
+            saved_networks_retry_interval: Some(60000),
+            dhcp_timeout: None,
 
             esp_reset_timeout: None,
+            portal_timeout: None,
+            portal_fallback: None,
+            #[cfg(feature = "reachability")]
+            reachability: None,
             esp_restart_after_connection: false,
 
-            wifi_conn_signal: None,
+            factory_reset_token: None,
+            factory_reset_gpio: None,
+            factory_reset_keys: &[],
+
+            reset_portal_trigger: None,
+            provisioning_fields: &[],
         }
     }
 }
 
-pub struct WmReturn {
+pub struct WmReturn<T = serde_json::Value> {
     pub sta_stack: Stack<'static>,
-    pub data: Option<serde_json::Value>,
+
+    /// Additional data sent with wifi credentials (`None` if no data was sent
+    /// or saved data doesn't match `T`)
+    pub data: Option<T>,
+
+    /// Ip address at the time `init_wm` returned (see `WmReturn::current_ip`)
     pub ip_address: [u8; 4],
 
-    pub(crate) stop_signal: Rc<Signal<CriticalSectionRawMutex, bool>>,
+    pub(crate) commands: Rc<WmCommands>,
+    pub(crate) nvs: Option<Nvs>,
+    pub(crate) factory_reset_keys: &'static [&'static str],
 }
 
 impl WmReturn {
+    /// Deserializes setup data into user type (`None` if there is no data or it doesn't match)
+    pub(crate) fn with_data_type<T: DeserializeOwned>(self) -> WmReturn<T> {
+        let data = self
+            .data
+            .and_then(|data| match serde_json::from_value::<T>(data) {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    log::warn!("Saved data doesn't match data type: {e:?}");
+                    None
+                }
+            });
+
+        WmReturn {
+            sta_stack: self.sta_stack,
+            data,
+            ip_address: self.ip_address,
+            commands: self.commands,
+            nvs: self.nvs,
+            factory_reset_keys: self.factory_reset_keys,
+        }
+    }
+}
+
+impl<T> WmReturn<T> {
+    /// Subscribes to wifimanager events (same as `subscribe`)
+    pub fn subscribe(&self) -> Option<WmEventSubscriber> {
+        crate::events::subscribe()
+    }
+
+    fn send_command(&self, command: WmCommand) {
+        if self.commands.try_send(command).is_err() {
+            log::error!("Wifimanager command queue is full, {command:?} dropped");
+        }
+    }
+
+    /// Current station ip address (`None` if dhcp lease was lost)
+    pub fn current_ip(&self) -> Option<[u8; 4]> {
+        self.sta_stack
+            .config_v4()
+            .map(|config| config.address.address().octets())
+    }
+
     // Disconnects from current wifi and stops wifi radio
     pub fn stop_radio(&self) {
-        self.stop_signal.signal(true);
+        self.send_command(WmCommand::StopRadio);
     }
 
     // Starts radio and reconnect to wifi
     // You can only use it after `stop_radio()`
     pub fn restart_radio(&self) {
-        self.stop_signal.signal(false);
+        self.send_command(WmCommand::RestartRadio);
+    }
+
+    // Starts setup portal (ap and/or ble) again, radio is restarted if it was stopped.
+    // Station stays connected while portal is running (except enterprise networks,
+    // ap cannot run alongside them). Portal stops after new network is connected and saved,
+    // after `WmSettings::portal_timeout` or after `stop_portal()`.
+    pub fn start_portal(&self) {
+        self.send_command(WmCommand::StartPortal);
+    }
+
+    // Stops setup portal started with `start_portal()` and reconnects to current network
+    pub fn stop_portal(&self) {
+        self.send_command(WmCommand::StopPortal);
+    }
+
+    /// Result of last internet reachability check (see `WmSettings::reachability`)
+    #[cfg(feature = "reachability")]
+    pub fn connectivity(&self) -> crate::Connectivity {
+        crate::reachability::connectivity()
+    }
+
+    // Switches station power save mode (e.g. `PowerSaveMode::None` during ota),
+    // it's kept after radio restart
+    pub fn set_power_save(&self, mode: PowerSaveMode) {
+        self.send_command(WmCommand::SetPowerSave(mode));
+    }
+
+    // Removes saved networks (and `WmSettings::factory_reset_keys` if `user_data` is set)
+    // from nvs. Restart esp afterwards to start setup portal.
+    pub async fn factory_reset(&self, user_data: bool) -> Result<()> {
+        let Some(ref nvs) = self.nvs else {
+            return Err(WmError::Other);
+        };
+
+        let user_keys = if user_data {
+            self.factory_reset_keys
+        } else {
+            &[]
+        };
+
+        crate::factory_reset(nvs, user_keys).await
     }
 }
 
-impl ::core::fmt::Debug for WmReturn {
+impl<T: ::core::fmt::Debug> ::core::fmt::Debug for WmReturn<T> {
     #[inline]
     fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
         f.debug_struct("WmReturn")
@@ -188,28 +866,177 @@ impl ::core::fmt::Debug for WmReturn {
     }
 }
 
+/// Max number of commands waiting for connection task
+pub(crate) const WM_COMMANDS_CAPACITY: usize = 8;
+
+/// Queue of commands handled by connection task (in order they were sent)
+pub(crate) type WmCommands = Channel<CriticalSectionRawMutex, WmCommand, WM_COMMANDS_CAPACITY>;
+
+/// Commands sent from `WmReturn` to connection task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WmCommand {
+    StopRadio,
+    RestartRadio,
+    StartPortal,
+    StopPortal,
+
+    /// Sent by reachability check and ip monitor to reassociate with ap
+    Reconnect,
+
+    SetPowerSave(PowerSaveMode),
+}
+
+/// Reason of failed connection attempt
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnFailReason {
+    /// Wrong password (or enterprise credentials)
+    AuthFailed,
+    NoApFound,
+    Timeout,
+
+    /// Associated with ap, but didn't get ip address
+    DhcpFailed,
+    AssociationRejected,
+    Other,
+}
+
+impl ConnFailReason {
+    /// Maps esp-idf (and 802.11) disconnect reason code
+    pub fn from_reason_code(code: u16) -> Self {
+        match code {
+            // 17 - 4-way handshake IE differs (key or rsn mismatch)
+            2 | 15 | 17 | 23 | 202 | 204 => ConnFailReason::AuthFailed,
+            201 | 210 | 211 | 212 => ConnFailReason::NoApFound,
+            203 | 205 => ConnFailReason::AssociationRejected,
+            200 => ConnFailReason::Timeout,
+            _ => ConnFailReason::Other,
+        }
+    }
+}
+
+impl From<&WifiError> for ConnFailReason {
+    fn from(value: &WifiError) -> Self {
+        match value {
+            WifiError::Disconnected(info) => Self::from_reason_code(info.reason as u16),
+            _ => ConnFailReason::Other,
+        }
+    }
+}
+
+/// State of connection attempt started by provisioning client.
+/// Serialized as `{"status": "failed", "reason": "auth_failed"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    #[default]
+    Idle,
+    Connecting,
+    Connected,
+    Failed(ConnFailReason),
+}
+
+pub(crate) type ConnStatusSubscriber<'a> = Subscriber<'a, NoopRawMutex, ConnectionStatus, 4, 4, 1>;
+
+/// Checks if setup data can be deserialized into user type (missing data is checked as `null`)
+pub(crate) type DataValidator = fn(Option<&serde_json::Value>) -> bool;
+
+pub(crate) fn validate_data<T: DeserializeOwned>(data: Option<&serde_json::Value>) -> bool {
+    match data {
+        Some(data) => T::deserialize(data).is_ok(),
+        None => T::deserialize(&serde_json::Value::Null).is_ok(),
+    }
+}
+
 pub struct WmInnerSignals {
     pub wifi_scan_res: Mutex<NoopRawMutex, alloc::string::String>,
 
     /// This is used to tell main task to connect to wifi
-    pub wifi_conn_info_sig: Signal<NoopRawMutex, alloc::vec::Vec<u8>>,
+    /// (setup info is validated before signalling)
+    pub wifi_conn_info_sig: Signal<NoopRawMutex, AutoSetupSettings>,
+
+    /// Status of last connection attempt (see `WmInnerSignals::set_conn_status`)
+    conn_status: BlockingMutex<NoopRawMutex, Cell<ConnectionStatus>>,
+    conn_status_pubsub: PubSubChannel<NoopRawMutex, ConnectionStatus, 4, 4, 1>,
 
-    /// This is used to tell ble task about conn result (return signal)
-    pub wifi_conn_res_sig: Signal<NoopRawMutex, bool>,
+    /// Station ip address assigned after successful connection
+    sta_ip: BlockingMutex<NoopRawMutex, Cell<Option<Ipv4Addr>>>,
+
+    /// This is used to tell main task to do factory reset
+    pub factory_reset_sig: Signal<NoopRawMutex, ()>,
 
     end_signal_pubsub: PubSubChannel<NoopRawMutex, (), 1, 16, 1>,
+
+    /// Bt peripheral released by ble task when it finishes
+    #[cfg(feature = "ble")]
+    pub bt_released: Signal<NoopRawMutex, esp_hal::peripherals::BT<'static>>,
+
+    data_validator: DataValidator,
+    provisioning_fields: &'static [ProvisioningField],
 }
 
 impl WmInnerSignals {
-    pub fn new() -> Self {
+    pub fn new(
+        data_validator: DataValidator,
+        provisioning_fields: &'static [ProvisioningField],
+    ) -> Self {
         Self {
             wifi_scan_res: Mutex::new(alloc::string::String::new()),
             wifi_conn_info_sig: Signal::new(),
-            wifi_conn_res_sig: Signal::new(),
+            conn_status: BlockingMutex::new(Cell::new(ConnectionStatus::Idle)),
+            conn_status_pubsub: PubSubChannel::new(),
+            sta_ip: BlockingMutex::new(Cell::new(None)),
+            factory_reset_sig: Signal::new(),
             end_signal_pubsub: PubSubChannel::new(),
+            #[cfg(feature = "ble")]
+            bt_released: Signal::new(),
+            data_validator,
+            provisioning_fields,
         }
     }
 
+    /// Parses setup string and checks if it's valid (including data matching user type)
+    pub fn validate_setup(
+        &self,
+        setup_info_buf: &[u8],
+    ) -> core::result::Result<AutoSetupSettings, ValidationError> {
+        validation::validate_setup(
+            setup_info_buf,
+            self.provisioning_fields,
+            self.data_validator,
+        )
+    }
+
+    pub fn provisioning_fields(&self) -> &'static [ProvisioningField] {
+        self.provisioning_fields
+    }
+
+    pub fn conn_status(&self) -> ConnectionStatus {
+        self.conn_status.lock(Cell::get)
+    }
+
+    /// Stores connection status and notifies status subscribers
+    pub fn set_conn_status(&self, status: ConnectionStatus) {
+        self.conn_status.lock(|s| s.set(status));
+        self.conn_status_pubsub
+            .immediate_publisher()
+            .publish_immediate(status);
+    }
+
+    pub fn sta_ip(&self) -> Option<Ipv4Addr> {
+        self.sta_ip.lock(Cell::get)
+    }
+
+    pub fn set_sta_ip(&self, ip: Option<Ipv4Addr>) {
+        self.sta_ip.lock(|s| s.set(ip));
+    }
+
+    /// Returns subscriber of connection status changes (`None` if all subscribers are taken)
+    #[allow(dead_code)]
+    pub fn conn_status_subscriber(&self) -> Option<ConnStatusSubscriber<'_>> {
+        self.conn_status_pubsub.subscriber().ok()
+    }
+
     /// Wait for end signal
     #[allow(dead_code)]
     pub async fn end_signalled(&self) {
@@ -1,11 +1,21 @@
-use crate::{structs::WmInnerSignals, Result, WmSettings};
+use crate::{
+    structs::{ConnFailReason, FactoryResetGpio, ReconnectPolicy, WmInnerSignals},
+    Result, WmError, WmSettings,
+};
 use alloc::rc::Rc;
-use embassy_executor::Spawner;
+use core::cell::Cell;
+#[cfg(feature = "ap")]
+use embassy_executor::{SpawnError, SpawnToken, Spawner};
 use embassy_net::Stack;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
 use embassy_time::{with_timeout, Duration, Timer};
 #[cfg(feature = "ap")]
 use esp_radio::wifi::Interface;
-use esp_radio::wifi::WifiController;
+use esp_radio::wifi::{
+    event::{EventExt, StaConnected},
+    WifiController,
+};
+use portable_atomic::{AtomicBool, Ordering};
 
 #[cfg(feature = "ap")]
 use embassy_net::{Config, Ipv4Cidr, StackResources, StaticConfigV4};
@@ -16,7 +26,7 @@ pub async fn spawn_ap(
     spawner: &Spawner,
     wm_signals: Rc<WmInnerSignals>,
     settings: WmSettings,
-    ap_interface: Interface<'static>,
+    ap_interface: &mut Option<Interface<'static>>,
 ) -> Result<()> {
     let ap_ip = embassy_net::Ipv4Address::new(192, 168, 4, 1);
     let ap_ip_config = Config::ipv4_static(StaticConfigV4 {
@@ -25,34 +35,107 @@ pub async fn spawn_ap(
         dns_servers: Default::default(),
     });
 
-    let (ap_stack, ap_runner) = embassy_net::new(
-        ap_interface,
-        ap_ip_config,
-        {
-            static STATIC_CELL: static_cell::StaticCell<StackResources<6>> =
-                static_cell::StaticCell::new();
-            STATIC_CELL.uninit().write(StackResources::<6>::new())
-        },
-        rng.random() as u64,
-    );
+    // ap stack is created once and reused by next setup portals, interface of first
+    // controller keeps working after controller is recreated (see `STA_STACK`)
+    static AP_STACK: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Stack<'static>>>> =
+        BlockingMutex::new(Cell::new(None));
 
-    spawner.spawn(crate::ap::ap_task(ap_runner, wm_signals.clone())?);
-    spawner.spawn(crate::ap::run_dhcp_server(ap_stack)?);
-    crate::http::run_http_server(spawner, ap_stack, wm_signals.clone(), settings.wifi_panel).await;
+    let ap_stack = match AP_STACK.lock(Cell::get) {
+        Some(ap_stack) => ap_stack,
+        None => {
+            let Some(ap_interface) = ap_interface.take() else {
+                return Err(WmError::Other);
+            };
+
+            let (ap_stack, ap_runner) = embassy_net::new(
+                ap_interface,
+                ap_ip_config,
+                {
+                    static STATIC_CELL: static_cell::StaticCell<StackResources<6>> =
+                        static_cell::StaticCell::new();
+                    STATIC_CELL.uninit().write(StackResources::<6>::new())
+                },
+                rng.random() as u64,
+            );
+
+            spawner.spawn(crate::ap::ap_task(ap_runner)?);
+            AP_STACK.lock(|s| s.set(Some(ap_stack)));
+            ap_stack
+        }
+    };
+
+    spawn_when_free(spawner, || crate::ap::run_dhcp_server(ap_stack)).await?;
+    crate::http::run_http_server(spawner, ap_stack, wm_signals.clone(), settings).await?;
 
     Ok(())
 }
 
+/// Max time to wait for task pool slot in `spawn_when_free`
+#[cfg(feature = "ap")]
+const TASK_POOL_WAIT: u64 = 5000;
+
+/// Spawns task, waiting if its pool is still used by tasks of previous setup portal
+/// (they finish on next poll after `WmInnerSignals::signal_end`)
+#[cfg(feature = "ap")]
+pub async fn spawn_when_free<S>(
+    spawner: &Spawner,
+    mut task: impl FnMut() -> core::result::Result<SpawnToken<S>, SpawnError>,
+) -> Result<()> {
+    let start_time = embassy_time::Instant::now();
+    loop {
+        match task() {
+            Ok(token) => {
+                spawner.spawn(token);
+                return Ok(());
+            }
+            Err(e) if start_time.elapsed().as_millis() >= TASK_POOL_WAIT => return Err(e.into()),
+            Err(_) => Timer::after_millis(50).await,
+        }
+    }
+}
+
+/// Reconnect delays of connection task (see `ReconnectPolicy`)
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+    rng: esp_hal::rng::Rng,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            attempt: 0,
+            rng: esp_hal::rng::Rng::new(),
+        }
+    }
+
+    /// Returns delay before next reconnect attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.policy.delay(self.attempt, self.rng.random());
+        self.attempt = self.attempt.saturating_add(1);
+
+        Duration::from_millis(delay)
+    }
+
+    /// Starts delays sequence again (after successful connection)
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Tries to connect until timeout, returns reason of last failed attempt
 pub async fn try_to_wifi_connect(
     controller: &mut WifiController<'static>,
     wifi_conn_timeout: u64,
-) -> bool {
+) -> core::result::Result<(), ConnFailReason> {
     let start_time = embassy_time::Instant::now();
+    let mut last_err = ConnFailReason::Timeout;
 
     loop {
         if start_time.elapsed().as_millis() > wifi_conn_timeout {
             log::warn!("Connect timeout (1)!");
-            return false;
+            return Err(last_err);
         }
 
         match with_timeout(
@@ -64,16 +147,17 @@ pub async fn try_to_wifi_connect(
             Ok(res) => match res {
                 Ok(_) => {
                     log::info!("Wifi connected!");
-                    return true;
+                    return Ok(());
                 }
                 Err(e) => {
                     log::info!("Failed to connect to wifi: {e:?}");
+                    last_err = ConnFailReason::from(&e);
                     Timer::after_millis(50).await;
                 }
             },
             Err(_) => {
                 log::warn!("Connect timeout (0)!");
-                return false;
+                return Err(ConnFailReason::Timeout);
             }
         }
     }
@@ -98,6 +182,57 @@ pub async fn wifi_wait_for_ip(stack: &Stack<'static>) -> [u8; 4] {
     ip
 }
 
+/// Bssid and channel of access point station connected to last time
+/// (set by `StaConnected` event handler)
+static CONNECTED_AP: BlockingMutex<CriticalSectionRawMutex, Cell<Option<([u8; 6], u8)>>> =
+    BlockingMutex::new(Cell::new(None));
+static CONNECTED_AP_HANDLER: AtomicBool = AtomicBool::new(false);
+
+/// Installs `StaConnected` event handler tracking connected access point
+/// (once, existing user handler is kept)
+pub fn track_connected_ap() {
+    if CONNECTED_AP_HANDLER.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    StaConnected::update_handler(|event| {
+        CONNECTED_AP.lock(|ap| ap.set(Some((event.bssid(), event.channel()))));
+    });
+}
+
+/// Bssid and channel of access point station is connected to
+pub fn connected_ap() -> Option<([u8; 6], u8)> {
+    CONNECTED_AP.lock(Cell::get)
+}
+
+/// Compares tokens in constant time (for equal length tokens)
+#[cfg(any(feature = "ap", feature = "ble"))]
+pub fn token_matches(provided: &str, expected: &str) -> bool {
+    if provided.len() != expected.len() {
+        return false;
+    }
+
+    provided
+        .bytes()
+        .zip(expected.bytes())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+/// Returns true if factory reset button was held for `FactoryResetGpio::hold_time` at boot
+pub async fn factory_reset_gpio_held(gpio: &FactoryResetGpio) -> bool {
+    let start_time = embassy_time::Instant::now();
+    while start_time.elapsed().as_millis() < gpio.hold_time {
+        if !gpio.pressed() {
+            return false;
+        }
+
+        Timer::after_millis(50).await;
+    }
+
+    true
+}
+
 pub fn get_efuse_mac() -> u64 {
     esp_hal::efuse::base_mac_address()
         .as_bytes()
@@ -0,0 +1,174 @@
+use crate::structs::{AutoSetupSettings, DataValidator, ProvisioningField};
+use alloc::string::String;
+use serde::Serialize;
+
+/// Max ssid length in bytes (802.11)
+const MAX_SSID_LEN: usize = 32;
+
+/// Wpa passphrase length (64 chars long psk has to be hex encoded raw key)
+const MIN_PSK_LEN: usize = 8;
+const MAX_PSK_LEN: usize = 63;
+
+/// Space taken by saved networks list around single network (see `SavedNetworks`)
+const SAVED_NETWORKS_OVERHEAD: usize = 64;
+
+/// Reason why setup string was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationError {
+    InvalidUtf8,
+    InvalidJson,
+    SsidEmpty,
+    SsidTooLong,
+    PskTooShort,
+    PskTooLong,
+
+    /// Passphrase contains non printable ascii characters
+    PskInvalidChars,
+    InvalidStaticIp,
+    InvalidEap,
+
+    /// Auth mode and pmf policy don't match (wpa3 without pmf)
+    InvalidSecurity,
+
+    /// Provisioning field (with given key) is missing or has invalid value
+    InvalidField(&'static str),
+
+    /// Data doesn't match user data type
+    InvalidData,
+
+    /// Network (with ca certificate and data) is too large to be saved in nvs
+    SetupTooLarge,
+}
+
+impl ValidationError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ValidationError::InvalidUtf8 => "Setup string is not valid utf-8",
+            ValidationError::InvalidJson => "Setup string is not valid json",
+            ValidationError::SsidEmpty => "Ssid cannot be empty",
+            ValidationError::SsidTooLong => "Ssid cannot be longer than 32 bytes",
+            ValidationError::PskTooShort => "Password must be at least 8 characters long",
+            ValidationError::PskTooLong => "Password cannot be longer than 63 characters",
+            ValidationError::PskInvalidChars => {
+                "Password can contain only printable ascii characters"
+            }
+            ValidationError::InvalidStaticIp => "Invalid static ip configuration",
+            ValidationError::InvalidEap => "Invalid enterprise credentials",
+            ValidationError::InvalidSecurity => "Wpa3 requires protected management frames",
+            ValidationError::InvalidField(_) => "Invalid value of provisioning field",
+            ValidationError::InvalidData => "Setup data doesn't match data type",
+            ValidationError::SetupTooLarge => "Setup is too large to be saved",
+        }
+    }
+
+    /// Json sent back to clients: `{"error": "psk_too_short", "message": "..."}`
+    /// (with `field` key for `InvalidField`)
+    pub fn to_json(&self) -> String {
+        let error = match self {
+            ValidationError::InvalidField(_) => serde_json::json!("invalid_field"),
+            _ => serde_json::to_value(self).unwrap_or_default(),
+        };
+
+        let mut json = serde_json::json!({ "error": error, "message": self.message() });
+        if let ValidationError::InvalidField(key) = self {
+            json["field"] = serde_json::json!(key);
+        }
+
+        json.to_string()
+    }
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ValidationError::InvalidField(key) => write!(f, "{} ({key})", self.message()),
+            _ => f.write_str(self.message()),
+        }
+    }
+}
+
+/// Parses and validates setup string sent by panel, ble or `env` feature
+pub(crate) fn validate_setup(
+    setup_info_buf: &[u8],
+    provisioning_fields: &'static [ProvisioningField],
+    data_validator: DataValidator,
+) -> Result<AutoSetupSettings, ValidationError> {
+    let setup_info_str =
+        core::str::from_utf8(setup_info_buf).map_err(|_| ValidationError::InvalidUtf8)?;
+    let setup_info: AutoSetupSettings =
+        serde_json::from_str(setup_info_str).map_err(|_| ValidationError::InvalidJson)?;
+
+    if setup_info.ssid.is_empty() {
+        return Err(ValidationError::SsidEmpty);
+    }
+    if setup_info.ssid.len() > MAX_SSID_LEN {
+        return Err(ValidationError::SsidTooLong);
+    }
+
+    match setup_info.eap {
+        Some(ref eap) if !eap.is_valid() => return Err(ValidationError::InvalidEap),
+        Some(_) => {}
+        None => validate_psk(&setup_info)?,
+    }
+
+    if !setup_info.is_security_valid() {
+        return Err(ValidationError::InvalidSecurity);
+    }
+
+    if !setup_info.static_ip.as_ref().is_none_or(|ip| ip.is_valid()) {
+        return Err(ValidationError::InvalidStaticIp);
+    }
+
+    validate_data(
+        setup_info.data.as_ref(),
+        provisioning_fields,
+        data_validator,
+    )?;
+
+    // saved networks list is stored in single nvs entry
+    let saved_len = serde_json::to_string(&setup_info)
+        .map_err(|_| ValidationError::InvalidJson)?
+        .len();
+    if !crate::nvs::string_fits(saved_len + SAVED_NETWORKS_OVERHEAD) {
+        return Err(ValidationError::SetupTooLarge);
+    }
+
+    Ok(setup_info)
+}
+
+/// Empty psk is allowed for open networks (if no minimum auth mode is set).
+/// Wpa passphrase is 8-63 printable ascii characters, 64 characters long psk is raw hex key.
+fn validate_psk(setup_info: &AutoSetupSettings) -> Result<(), ValidationError> {
+    let psk = &setup_info.psk;
+    if psk.is_empty() && setup_info.auth.is_none() {
+        return Ok(());
+    }
+
+    let is_raw_key = psk.len() == MAX_PSK_LEN + 1 && psk.bytes().all(|b| b.is_ascii_hexdigit());
+    if !is_raw_key && !psk.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+        return Err(ValidationError::PskInvalidChars);
+    }
+
+    match psk.len() {
+        len if len < MIN_PSK_LEN => Err(ValidationError::PskTooShort),
+        len if len > MAX_PSK_LEN && !is_raw_key => Err(ValidationError::PskTooLong),
+        _ => Ok(()),
+    }
+}
+
+fn validate_data(
+    data: Option<&serde_json::Value>,
+    provisioning_fields: &'static [ProvisioningField],
+    data_validator: DataValidator,
+) -> Result<(), ValidationError> {
+    if let Some(field) = provisioning_fields.iter().find(|f| !f.validate(data)) {
+        return Err(ValidationError::InvalidField(field.key));
+    }
+
+    if !data_validator(data) {
+        return Err(ValidationError::InvalidData);
+    }
+
+    Ok(())
+}